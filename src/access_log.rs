@@ -0,0 +1,113 @@
+use std::{
+    fmt::{self, Display, Write},
+    net::IpAddr,
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+
+/// Timestamp as Apache writes it, e.g. `10/Oct/2000:13:55:36 +0000`.
+const CLF_TIME: &[FormatItem] = format_description!(
+    "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+);
+
+/// How the server logs, and whether every request gets a line of its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Diagnostics only, no line per request.
+    #[default]
+    Text,
+    /// A Common Log Format line per request.
+    Clf,
+    /// As clf, followed by the Referer and User-Agent.
+    Combined,
+}
+
+/// What is known about a request once it has been answered. Anything the
+/// request never got as far as telling us is left out, and logged as `-`.
+pub(crate) struct AccessEntry<'a> {
+    /// Unix socket peers have no address to give.
+    pub client: Option<IpAddr>,
+    pub label: Option<&'a str>,
+    pub request_line: Option<&'a str>,
+    pub status: u16,
+    pub body_len: usize,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+impl AccessEntry<'_> {
+    /// The entry as a line in the given format, none when it logs no requests.
+    pub fn line(&self, format: LogFormat) -> Option<String> {
+        let common = match format {
+            LogFormat::Text => return None,
+            LogFormat::Clf | LogFormat::Combined => self.common(),
+        };
+        match format {
+            LogFormat::Combined => Some(format!(
+                "{} \"{}\" \"{}\"",
+                common,
+                Quoted(self.referer),
+                Quoted(self.user_agent)
+            )),
+            _ => Some(common),
+        }
+    }
+
+    fn common(&self) -> String {
+        let time = OffsetDateTime::now_utc()
+            .format(CLF_TIME)
+            .unwrap_or_else(|_| "-".to_string());
+        let body_len = match self.body_len {
+            0 => "-".to_string(),
+            len => len.to_string(),
+        };
+        format!(
+            "{} - {} [{}] \"{}\" {} {}",
+            self.client
+                .map_or("-".to_string(), |client| client.to_string()),
+            Bare(self.label),
+            time,
+            Quoted(self.request_line),
+            self.status,
+            body_len
+        )
+    }
+}
+
+/// A field between quotes, escaped so that it cannot end the quotes early
+/// or break the line.
+struct Quoted<'a>(Option<&'a str>);
+
+impl Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        escape(f, self.0, false)
+    }
+}
+
+/// A field standing on its own, where a space would also split it in two.
+struct Bare<'a>(Option<&'a str>);
+
+impl Display for Bare<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        escape(f, self.0, true)
+    }
+}
+
+fn escape(f: &mut fmt::Formatter<'_>, value: Option<&str>, bare: bool) -> fmt::Result {
+    let value = match value {
+        Some(value) if !value.is_empty() => value,
+        _ => return f.write_char('-'),
+    };
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => write!(f, "\\{}", c)?,
+            ' ' if bare => f.write_str("\\x20")?,
+            c if c.is_control() => write!(f, "\\x{:02x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    Ok(())
+}
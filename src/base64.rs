@@ -0,0 +1,50 @@
+//! Just enough base64 for generating secrets and reading Basic credentials.
+
+const STANDARD: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const URL_SAFE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// URL-safe base64 without padding.
+pub(crate) fn encode_url(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (i, byte)| group | (*byte as u32) << (16 - 8 * i));
+        // three bytes make four characters, a shorter chunk makes one more
+        // character than it has bytes
+        for i in 0..=chunk.len() {
+            encoded.push(URL_SAFE[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Standard base64, padded or not. None for anything else.
+pub(crate) fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    let unpadded = match encoded.len() % 4 {
+        0 => encoded
+            .strip_suffix(b"==")
+            .or_else(|| encoded.strip_suffix(b"="))
+            .unwrap_or(encoded),
+        _ => encoded,
+    };
+    if unpadded.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(unpadded.len() / 4 * 3 + 2);
+    for chunk in unpadded.chunks(4) {
+        let mut group = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = STANDARD.iter().position(|a| a == c)? as u32;
+            group |= value << (18 - 6 * i);
+        }
+        // four characters make three bytes, a shorter chunk one byte fewer
+        // than it has characters
+        for i in 0..chunk.len() - 1 {
+            decoded.push((group >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(decoded)
+}
@@ -0,0 +1,130 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+
+/// Hosts starting with this are taken to be the path of a Unix socket, as
+/// they are when serving.
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Read and write timeout applied to every request.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest response read, mellon never sends more than a templated body.
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+/// A bare-bones client for talking to a mellon instance, one request per
+/// connection just as the server answers them.
+pub struct MellonClient {
+    host: String,
+    timeout: Duration,
+}
+
+/// The status, headers and body a mellon instance answered with.
+#[derive(Debug)]
+pub struct ClientResponse {
+    pub status: u16,
+    /// In the order received, names as sent.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    /// The first value of a header, the name is matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+impl MellonClient {
+    /// A client for the host mellon serves on, `HOST:PORT` or
+    /// `unix:/path/to/socket`.
+    pub fn new(host: impl Into<String>) -> Self {
+        MellonClient {
+            host: host.into(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        MellonClient { timeout, ..self }
+    }
+
+    pub fn get(&self, path: &str, token: Option<&str>) -> Result<ClientResponse> {
+        self.request("GET", path, token)
+    }
+
+    /// Sends a request presenting the token, if any, as a bearer token.
+    pub fn request(&self, method: &str, path: &str, token: Option<&str>) -> Result<ClientResponse> {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, self.host);
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        request.push_str("Connection: close\r\n\r\n");
+
+        let mut stream = self.connect()?;
+        stream.write_all(request.as_bytes())?;
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE_BYTES).read_to_end(&mut response)?;
+        parse_response(&response)
+    }
+
+    fn connect(&self) -> Result<Box<dyn Stream>> {
+        if let Some(path) = self.host.strip_prefix(UNIX_SOCKET_PREFIX) {
+            return connect_unix(path, self.timeout);
+        }
+        let stream = TcpStream::connect(&self.host)
+            .map_err(|e| anyhow!("Unable to connect to {}: {}", self.host, e))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str, timeout: Duration) -> Result<Box<dyn Stream>> {
+    let stream = std::os::unix::net::UnixStream::connect(path)
+        .map_err(|e| anyhow!("Unable to connect to {}: {}", path, e))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str, _timeout: Duration) -> Result<Box<dyn Stream>> {
+    Err(anyhow!("Unix sockets are not supported on this platform"))
+}
+
+fn parse_response(response: &[u8]) -> Result<ClientResponse> {
+    let head_len = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("Response ended before its headers did"))?;
+    let head = String::from_utf8_lossy(&response[..head_len]);
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("Invalid status line: {}", status_line))?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect();
+    Ok(ClientResponse {
+        status,
+        headers,
+        body: response[head_len + 4..].to_vec(),
+    })
+}
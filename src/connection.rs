@@ -0,0 +1,149 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, TcpStream},
+    time::Duration,
+};
+
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::simple_server::ServerOptions;
+
+/// A client connection the server can answer, over TCP or a Unix socket.
+pub trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Applies the socket level options that make sense for the transport.
+    fn configure(&self, options: &ServerOptions) -> io::Result<()>;
+
+    /// Describes the other end of the connection for logging.
+    fn peer(&self) -> Option<String>;
+
+    /// Address of the other end, if it has one.
+    fn peer_ip(&self) -> Option<IpAddr>;
+
+    /// Signals that nothing more will be written.
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn configure(&self, options: &ServerOptions) -> io::Result<()> {
+        self.set_nodelay(options.tcp_nodelay)?;
+        if let Some(idle) = options.tcp_keepalive {
+            SockRef::from(self).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(())
+    }
+
+    fn peer(&self) -> Option<String> {
+        self.peer_addr().ok().map(|peer| peer.to_string())
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|peer| peer.ip())
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+pub use unix::UnixSocketListener;
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs,
+        io::{self, ErrorKind},
+        os::unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net::{Incoming, UnixListener, UnixStream},
+        },
+        path::PathBuf,
+        time::Duration,
+    };
+
+    use anyhow::{anyhow, Result};
+
+    use super::{Connection, IpAddr, Shutdown};
+    use crate::shutdown;
+    use crate::simple_server::ServerOptions;
+
+    /// Owner and group only, the proxy is expected to share our group.
+    const SOCKET_MODE: u32 = 0o660;
+
+    /// A listening Unix socket, whose file is removed again once dropped.
+    pub struct UnixSocketListener {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl UnixSocketListener {
+        pub fn bind(path: PathBuf) -> Result<Self> {
+            // a socket file left behind by an earlier run would make bind fail
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(&path)?,
+                Ok(_) => {
+                    return Err(anyhow!(
+                        "Refusing to replace {}, it is not a socket",
+                        path.display()
+                    ))
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            let listener = UnixListener::bind(&path)?;
+            let socket = UnixSocketListener { listener, path };
+            fs::set_permissions(&socket.path, fs::Permissions::from_mode(SOCKET_MODE))?;
+            shutdown::remove_on_termination(socket.path.clone())?;
+            Ok(socket)
+        }
+
+        pub fn incoming(&self) -> Incoming<'_> {
+            self.listener.incoming()
+        }
+    }
+
+    impl Drop for UnixSocketListener {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    impl Connection for UnixStream {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            UnixStream::set_read_timeout(self, timeout)
+        }
+
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            UnixStream::set_write_timeout(self, timeout)
+        }
+
+        fn configure(&self, _options: &ServerOptions) -> io::Result<()> {
+            // the TCP options have no meaning on a Unix socket
+            Ok(())
+        }
+
+        fn peer(&self) -> Option<String> {
+            None
+        }
+
+        fn peer_ip(&self) -> Option<IpAddr> {
+            None
+        }
+
+        fn shutdown_write(&self) -> io::Result<()> {
+            self.shutdown(Shutdown::Write)
+        }
+    }
+}
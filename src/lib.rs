@@ -0,0 +1,27 @@
+//! Token store and auth server behind the `mellon` binary, for embedding
+//! mellon's token checks in other programs.
+
+pub mod access_log;
+mod base64;
+pub mod client;
+mod connection;
+pub mod forwarded;
+pub mod logging;
+pub mod pid_file;
+pub mod response_template;
+mod shutdown;
+pub mod simple_server;
+pub mod tokens;
+mod worker_pool;
+
+pub use access_log::LogFormat;
+pub use client::{ClientResponse, MellonClient};
+pub use simple_server::{BasicSecret, MellonServer, ServerOptions};
+pub use tokens::clock::{Clock, MockClock, SystemClock};
+pub use tokens::secret_generator::{
+    RandomGenerator, SecretFormat, SecretGenerator, SequenceGenerator, UuidGenerator,
+};
+pub use tokens::signing_key::SigningKey;
+pub use tokens::store_error::TokenStoreError;
+pub use tokens::token::Token;
+pub use tokens::token_store::{StoreLayout, StoreOptions, TokenOptions, TokenStore, TokenUse};
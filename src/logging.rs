@@ -0,0 +1,71 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use anyhow::{anyhow, Result};
+
+/// Where server diagnostics go. Until a log file is configured they are
+/// written to stderr.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    reopen: Arc<AtomicBool>,
+}
+
+static LOG_FILE: OnceLock<Mutex<LogFile>> = OnceLock::new();
+
+/// Sends log lines to the file at `path`, reopening it on SIGUSR1 so that
+/// tools such as logrotate can move it out of the way.
+pub fn log_to_file(path: PathBuf) -> Result<()> {
+    let file = open(&path)?;
+    let reopen = Arc::new(AtomicBool::new(false));
+    // the handler only raises the flag, the reopening happens on the next write
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&reopen))?;
+    LOG_FILE
+        .set(Mutex::new(LogFile { path, file, reopen }))
+        .map_err(|_| anyhow!("Log file has already been configured"))
+}
+
+pub fn write_line(args: std::fmt::Arguments) {
+    let Some(log_file) = LOG_FILE.get() else {
+        eprintln!("{}", args);
+        return;
+    };
+    let mut log_file = match log_file.lock() {
+        Ok(log_file) => log_file,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if log_file.reopen.swap(false, Ordering::SeqCst) {
+        match open(&log_file.path) {
+            Ok(file) => log_file.file = file,
+            Err(e) => eprintln!("Failed to reopen log file: {}", e),
+        }
+    }
+    if writeln!(log_file.file, "{}", args).is_err() {
+        // losing the line entirely would be worse than it landing on stderr
+        eprintln!("{}", args);
+    }
+}
+
+fn open(path: &PathBuf) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Unable to open log file {}: {}", path.display(), e))
+}
+
+/// Writes a line to the server log, like `eprintln!`.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::logging::write_line(format_args!($($arg)*))
+    };
+}
@@ -1,36 +1,288 @@
-use simple_server::MellonServer;
-use tokens::token_store::TokenStore;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-mod simple_server;
-mod tokens;
+use anyhow::{anyhow, Result};
+use mellon::access_log::LogFormat;
+use mellon::forwarded::Cidr;
+use mellon::logging;
+use mellon::pid_file::PidFile;
+use mellon::response_template::ResponseTemplate;
+use mellon::simple_server::{
+    default_workers, BasicSecret, MellonServer, ServerOptions, DEFAULT_BIND_RETRY_DELAY_MS,
+    DEFAULT_MAX_REQUEST_LINE, DEFAULT_TIMEOUT_SECS,
+};
+use mellon::tokens::{
+    scope::Scope,
+    secret_generator::{RandomGenerator, SecretFormat, DEFAULT_SECRET_BYTES},
+    signing_key::SigningKey,
+    store_diff::{DesiredToken, StoreDiff},
+    store_error::TokenStoreError,
+    store_format::StoreFormat,
+    store_fsck::StoreCheck,
+    store_import::{EnvToken, StoreImport},
+    store_key::StoreKey,
+    token::{MatchKind, Token, Tombstone},
+    token_store::{
+        epoch_now, StoreLayout, StoreOptions, TokenOptions, TokenStore, DEFAULT_MAX_LABEL_LEN,
+        DEFAULT_MAX_SECRET_LEN,
+    },
+};
 
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 
 use prettytable::{row, Cell, Row, Table};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 #[derive(Parser)]
 #[command(name = "mellon")]
 #[command(bin_name = "mellon")]
-#[command(version = "0.0.1")]
+#[command(version = VERSION)]
 #[command(author = "Daniel du Plessis")]
 #[command(about = "A small, simple, fast auth service")]
 #[command(long_about = THE_DOORS_OF_DURIN)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// File holding the key used to encrypt the token store.
+    /// Takes precedence over the MELLON_STORE_KEY environment variable.
+    #[clap(long, value_name = "PATH", global = true)]
+    store_key_file: Option<PathBuf>,
+
+    /// Format the token store is written in, either is accepted on load.
+    #[clap(long, value_enum, global = true, default_value_t = StoreFormat::Colon)]
+    store_format: StoreFormat,
+
+    /// File holding the key new tokens are signed with, instead of being
+    /// given a stored secret. Takes precedence over MELLON_HMAC_KEY.
+    #[clap(long, value_name = "PATH", global = true)]
+    hmac_key_file: Option<PathBuf>,
+
+    /// Token store file, or an http(s) URL to fetch a read only token list from.
+    #[clap(long, value_name = "PATH|URL", global = true, default_value = STORE_FILE_PATH)]
+    store: String,
+
+    /// File holding the Authorization header value sent when fetching the
+    /// store, e.g. `Bearer <token>`. Takes precedence over MELLON_STORE_AUTH.
+    #[clap(long, value_name = "PATH", global = true)]
+    store_auth_file: Option<PathBuf>,
+
+    /// Write to the token store even when the file there does not look like
+    /// one, such as a store from before stores were marked as such.
+    #[clap(long, global = true)]
+    force_store: bool,
+
+    /// Longest label, in bytes, a new token may be given.
+    #[clap(long, value_name = "BYTES", global = true, default_value_t = DEFAULT_MAX_LABEL_LEN)]
+    max_label_len: usize,
+
+    /// Longest secret, in bytes, a new token may be given.
+    #[clap(long, value_name = "BYTES", global = true, default_value_t = DEFAULT_MAX_SECRET_LEN)]
+    max_secret_len: usize,
+
+    /// How generated secrets are written out.
+    #[clap(long, value_enum, global = true, default_value_t)]
+    secret_format: SecretFormat,
+
+    /// Random bytes in a generated hex or base64 secret.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        global = true,
+        default_value_t = DEFAULT_SECRET_BYTES,
+        value_parser = parse_secret_bytes
+    )]
+    secret_bytes: usize,
+
+    /// Store new exact tokens as a SHA-256 hash of their secret, which is
+    /// then only shown once, when the token is created.
+    #[clap(long, global = true)]
+    hash_secrets: bool,
 }
 
+// parsed once per run, boxing the serve arguments would gain nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Starts the auth server.
     Serve {
-        /// Hostname for the server.
+        /// Hostname for the server, or `unix:<path>` to listen on a Unix socket.
         #[clap(
             value_name = "HOSTNAME",
             required = false,
             default_value = "localhost:8090"
         )]
         host: Option<String>,
+
+        /// Maximum length in bytes of the request line before responding 414.
+        #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_REQUEST_LINE)]
+        max_request_line: usize,
+
+        /// Log a warning for requests taking longer than this many milliseconds.
+        #[clap(long, value_name = "MS")]
+        slow_threshold_ms: Option<u64>,
+
+        /// Disable Nagle's algorithm so small responses are sent immediately.
+        #[clap(long, value_name = "BOOL", default_value_t = true, action = ArgAction::Set)]
+        tcp_nodelay: bool,
+
+        /// Enable TCP keepalive, probing after the connection idles this many seconds.
+        #[clap(long, value_name = "SECS")]
+        tcp_keepalive: Option<u64>,
+
+        /// Log what would be rejected, but respond 200 to every request.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Threads connections are served on, one per CPU by default.
+        #[clap(long, value_name = "THREADS", value_parser = clap::value_parser!(u16).range(1..))]
+        workers: Option<u16>,
+
+        /// Retry binding the host this many times if it is still in use.
+        #[clap(long, value_name = "ATTEMPTS", default_value_t = 0)]
+        bind_retry: u32,
+
+        /// Milliseconds to wait before the first bind retry, doubling after each.
+        #[clap(long, value_name = "MS", default_value_t = DEFAULT_BIND_RETRY_DELAY_MS)]
+        bind_retry_delay: u64,
+
+        /// Write server logs to this file instead of stderr.
+        /// The file is reopened on SIGUSR1 to play along with log rotation.
+        #[clap(long, value_name = "PATH")]
+        log_file: Option<PathBuf>,
+
+        /// Log a line for every request in Common or Combined Log Format, text logs none.
+        #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+        log_format: LogFormat,
+
+        /// Believe X-Forwarded-For from peers in this range when logging the client, may be given more than once.
+        #[clap(long, value_name = "CIDR")]
+        trusted_proxy: Vec<Cidr>,
+
+        /// Give up on a request with 408 when reading it takes longer than this many seconds.
+        #[clap(long, value_name = "SECS")]
+        max_request_time: Option<u64>,
+
+        /// Give up on a request with 408 when the client sends nothing for this many seconds.
+        #[clap(long, value_name = "SECS", default_value_t = DEFAULT_TIMEOUT_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+        read_timeout: u64,
+
+        /// Give up on a response when the client takes none of it for this many seconds.
+        #[clap(long, value_name = "SECS", default_value_t = DEFAULT_TIMEOUT_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+        write_timeout: u64,
+
+        /// Body to send with a 200, inline or as @<path>. {label} is replaced with the token's label.
+        #[clap(long, value_name = "TEMPLATE")]
+        ok_body: Option<String>,
+
+        /// Body to send with a 401 or 403, inline or as @<path>.
+        #[clap(long, value_name = "TEMPLATE")]
+        unauthorized_body: Option<String>,
+
+        /// Content-Type sent with response bodies, inferred from the template when not given.
+        #[clap(long, value_name = "TYPE")]
+        body_content_type: Option<String>,
+
+        /// Serve a single connection then exit, 0 if it was let in, 1 if it was turned away and 2 otherwise.
+        #[clap(long)]
+        once: bool,
+
+        /// Start even when the store holds no tokens, rather than refusing to.
+        #[clap(long)]
+        allow_empty_store: bool,
+
+        /// Compact the store on start up, dropping tombstones revoked more than this many seconds ago.
+        #[clap(long, value_name = "SECS")]
+        keep_revoked_for: Option<u64>,
+
+        /// Drop expired tokens from the store on start up, rather than
+        /// keeping them on disk while turning them away.
+        #[clap(long)]
+        prune_expired_on_load: bool,
+
+        /// Write the port listened on to this file, e.g. after binding port 0.
+        #[clap(long, value_name = "PATH")]
+        port_file: Option<PathBuf>,
+
+        /// Only check tokens on requests for exactly this path, answering 404 for any other.
+        #[clap(long, value_name = "PATH")]
+        guard_path: Option<String>,
+
+        /// Answer RFC 7662 token introspection on POST /introspect, for
+        /// callers presenting a valid token of their own.
+        #[clap(long)]
+        introspection: bool,
+
+        /// Answer requests for this path with a 200 without checking for a
+        /// token, e.g. /health for a load balancer.
+        #[clap(long, value_name = "PATH")]
+        health_path: Option<String>,
+
+        /// Let an admin reload the store with POST /admin/reload, presenting
+        /// the token from --admin-token-file or MELLON_ADMIN_TOKEN.
+        #[clap(long)]
+        admin_reload: bool,
+
+        /// File holding the bearer token for --admin-reload. Takes precedence
+        /// over MELLON_ADMIN_TOKEN.
+        #[clap(long, value_name = "PATH", requires = "admin_reload")]
+        admin_token_file: Option<PathBuf>,
+
+        /// Name the token a request was let in by in an X-Auth-Label header,
+        /// or X-Forwarded-User with --forward-auth.
+        #[clap(long)]
+        label_header: bool,
+
+        /// Act as a Traefik ForwardAuth server, taking the original request
+        /// from X-Forwarded-Method and X-Forwarded-Uri.
+        #[clap(long)]
+        forward_auth: bool,
+
+        /// Also accept HTTP Basic credentials, matching their password or the
+        /// whole of user:password against the store.
+        #[clap(long, value_enum, value_name = "PART")]
+        accept_basic: Option<BasicSecret>,
+
+        /// Let every request through while the token store cannot be consulted.
+        #[clap(long, overrides_with = "fail_closed")]
+        fail_open: bool,
+
+        /// Turn every request away while the token store cannot be consulted, the default.
+        /// Whichever of this and --fail-open is given last wins.
+        #[clap(long, overrides_with = "fail_open")]
+        fail_closed: bool,
+
+        /// Check the store file for changes this often and reload it when it changes, 0 never checks.
+        #[clap(long, value_name = "SECS", default_value_t = 0)]
+        reload_interval: u64,
+
+        /// Write the process id to this file, removing it again on shutdown.
+        #[clap(long, value_name = "PATH")]
+        pid_file: Option<PathBuf>,
+
+        /// Replace a PID file even if the process it names is still running.
+        #[clap(long, requires = "pid_file")]
+        force: bool,
+
+        /// Send a Server header, mellon and its version unless a value is given.
+        #[clap(
+            long,
+            value_name = "VALUE",
+            num_args = 0..=1,
+            value_parser = parse_header_value,
+            conflicts_with = "no_server_header"
+        )]
+        server_header: Option<Option<String>>,
+
+        /// Send no Server header, the default.
+        #[clap(long)]
+        no_server_header: bool,
     },
 
     /// Manage tokens by adding or removing.
@@ -38,40 +290,504 @@ enum Commands {
         #[clap(subcommand)]
         action: TokenCommands,
     },
+
+    /// Maintain the token store file itself.
+    Store {
+        #[clap(subcommand)]
+        action: StoreCommands,
+    },
+
+    /// Print version and build information.
+    Version {
+        /// Print as JSON for machine consumption.
+        #[clap(long)]
+        json: bool,
+    },
 }
 
 #[derive(Debug, Subcommand)]
 enum TokenCommands {
     /// Add a new token.
     Add {
-        /// The label of the token to add
+        /// The label of the token to add, read from stdin when left out.
+        #[clap(conflicts_with = "batch")]
+        token_label: Option<String>,
+
+        /// Read a label per line from stdin and print "LABEL<TAB>SECRET" for
+        /// each token added. Labels already taken are skipped.
+        #[clap(long, conflicts_with_all = ["secret", "copy", "json"])]
+        batch: bool,
+
+        /// Attach a note to the token, may be repeated.
+        #[clap(long, value_name = "KEY=VALUE", value_parser = parse_annotation)]
+        annotate: Vec<(String, String)>,
+
+        /// Reject the token once it has been used this many times.
+        #[clap(long, value_name = "N")]
+        max_uses: Option<u64>,
+
+        /// Only allow requests matching "METHOD PATH", may be repeated. Any method is "*".
+        #[clap(long, value_name = "SCOPE", value_parser = parse_scope)]
+        scope: Vec<Scope>,
+
+        /// Expire the token this many seconds from now.
+        #[clap(long, value_name = "SECS", conflicts_with = "expires_at")]
+        ttl: Option<u64>,
+
+        /// Expire the token at an RFC3339 timestamp, e.g. 2025-06-30T23:59:59Z.
+        #[clap(long, value_name = "TIMESTAMP", value_parser = parse_expires_at)]
+        expires_at: Option<u64>,
+
+        /// Put the new secret on the clipboard rather than printing it.
+        #[clap(long)]
+        copy: bool,
+
+        /// Print the new token as a JSON object and nothing else, errors
+        /// included, which go to stderr.
+        #[clap(long, conflicts_with = "copy")]
+        json: bool,
+
+        /// Use this secret rather than generating one.
+        #[clap(long, value_name = "SECRET")]
+        secret: Option<String>,
+
+        /// How presented tokens are compared against the secret. With regex
+        /// the secret is a pattern that every token string it fully matches is let in by.
+        #[clap(
+            long = "match",
+            value_enum,
+            default_value_t,
+            requires_if("regex", "secret")
+        )]
+        matching: MatchKind,
+    },
+
+    /// Replace the secret of an existing token, keeping its label.
+    Rotate {
+        /// The label of the token to give a new secret.
         token_label: String,
+
+        /// Put the new secret on the clipboard rather than printing it.
+        #[clap(long)]
+        copy: bool,
+
+        /// Print the token as a JSON object and nothing else, errors
+        /// included, which go to stderr.
+        #[clap(long, conflicts_with = "copy")]
+        json: bool,
     },
 
     /// Revoke an existing token by its label.
     Rescind {
         /// The label of the token to remove.
-        token_label: String,
+        #[clap(
+            required_unless_present_any = ["by_secret", "by_token"],
+            conflicts_with_all = ["by_secret", "by_token"]
+        )]
+        token_label: Option<String>,
+
+        /// Read a secret from stdin and remove the token holding it, for
+        /// when a secret leaks without its label.
+        #[clap(long, conflicts_with = "by_token")]
+        by_secret: bool,
+
+        /// Remove the token holding this secret, as --by-secret does without
+        /// reading it from stdin.
+        #[clap(long, value_name = "SECRET")]
+        by_token: Option<String>,
+
+        /// Keep a record of the revoked token in the store.
+        #[clap(long)]
+        keep_tombstones: bool,
     },
 
     /// List all tokens previously issued
-    List {},
+    List {
+        /// Also list tokens that have been rescinded with a tombstone.
+        #[clap(long)]
+        include_revoked: bool,
+
+        /// Include token annotations, never shortening anything to fit.
+        #[clap(long)]
+        wide: bool,
+
+        /// Never shorten labels or change the layout to fit the terminal.
+        #[clap(long)]
+        no_truncate: bool,
+
+        /// How to print the tokens.
+        #[clap(long, value_enum, default_value_t = ListFormat::Table)]
+        format: ListFormat,
+
+        /// Print each token as it is reached instead of building the whole
+        /// listing first. Tables come out tab-separated.
+        #[clap(long)]
+        stream: bool,
+
+        /// Only list tokens past their expiry.
+        #[clap(long, conflicts_with_all = ["active", "expiring_within"])]
+        expired: bool,
+
+        /// Only list tokens that have not expired.
+        #[clap(long, conflicts_with = "expiring_within")]
+        active: bool,
+
+        /// Only list tokens that have not expired yet but will within this many seconds.
+        #[clap(long, value_name = "SECS")]
+        expiring_within: Option<u64>,
+
+        /// Order tokens by label or by expiry, soonest first. Unordered by default.
+        #[clap(long, value_enum)]
+        sort: Option<ListSort>,
+    },
+
+    /// Show everything recorded about a token.
+    Inspect {
+        /// The label of the token to show.
+        token_label: String,
+    },
+
+    /// Read a token from stdin and print the label it belongs to.
+    Whoami,
+
+    /// Compare the store against a file listing the tokens that should exist.
+    /// Each line holds a bare label, or a label and secret as in the store.
+    Diff {
+        /// File describing the desired tokens.
+        desired_file: PathBuf,
+
+        /// Add, update and rescind tokens until the store matches the file.
+        #[clap(long)]
+        apply: bool,
+    },
+
+    /// Create tokens from the LABEL=SECRET lines of a .env file.
+    ImportEnv {
+        /// The .env file to import.
+        env_file: PathBuf,
+
+        /// Give existing tokens the secret from the file.
+        #[clap(long)]
+        replace: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ListFormat {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ListSort {
+    Label,
+    Expires,
+}
+
+#[derive(Clone, Copy)]
+enum ExpiryFilter {
+    Expired,
+    Active,
+    /// Carries how many seconds from now.
+    ExpiringWithin(u64),
+}
+
+impl ExpiryFilter {
+    fn admits(&self, token: &Token, now: u64) -> bool {
+        match self {
+            ExpiryFilter::Expired => token.is_expired(now),
+            ExpiryFilter::Active => !token.is_expired(now),
+            ExpiryFilter::ExpiringWithin(secs) => {
+                !token.is_expired(now)
+                    && token
+                        .expires_at
+                        .is_some_and(|expires_at| expires_at <= now.saturating_add(*secs))
+            }
+        }
+    }
+}
+
+/// What `list` was asked to show.
+struct Listing {
+    include_revoked: bool,
+    wide: bool,
+    /// Whether the table may be shortened to fit the terminal.
+    truncate: bool,
+    format: ListFormat,
+    filter: Option<ExpiryFilter>,
+    sort: Option<ListSort>,
+}
+
+impl Listing {
+    /// The tokens to list. They are only gathered up when they have to be
+    /// sorted, otherwise they come straight from the store.
+    fn tokens<'a>(
+        &self,
+        token_store: &'a TokenStore,
+    ) -> Result<Box<dyn Iterator<Item = &'a Token> + 'a>> {
+        let now = token_store.now().unwrap_or_default();
+        let filter = self.filter;
+        let tokens = token_store
+            .iter()?
+            .filter(move |token| filter.is_none_or(|filter| filter.admits(token, now)));
+        let Some(sort) = self.sort else {
+            return Ok(Box::new(tokens));
+        };
+        let mut tokens: Vec<_> = tokens.collect();
+        match sort {
+            ListSort::Label => tokens.sort_by(|a, b| a.label.cmp(&b.label)),
+            // tokens that never expire go last
+            ListSort::Expires => tokens.sort_by(|a, b| {
+                let expiry = |token: &Token| token.expires_at.unwrap_or(u64::MAX);
+                expiry(a)
+                    .cmp(&expiry(b))
+                    .then_with(|| a.label.cmp(&b.label))
+            }),
+        }
+        Ok(Box::new(tokens.into_iter()))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum StoreCommands {
+    /// Re-encrypt the store with a new key.
+    /// The new key is read from the file given, or from MELLON_NEW_STORE_KEY.
+    Rekey {
+        /// File holding the new store key.
+        #[clap(long, value_name = "PATH")]
+        new_key_file: Option<PathBuf>,
+    },
+
+    /// Drop old tombstones from the store file.
+    Compact {
+        /// Keep tombstones revoked within this many seconds.
+        #[clap(long, value_name = "SECS", default_value_t = 0)]
+        keep_revoked_for: u64,
+    },
+
+    /// Check the store file for duplicate labels and secrets.
+    Fsck {
+        /// Rename duplicate labels and drop duplicate secrets, rewriting the file.
+        #[clap(long)]
+        fix: bool,
+    },
+
+    /// Rewrite a store from an earlier version as it is written now, in --store-format.
+    Migrate {
+        /// Copy the store file to <store>.<epoch>.bak before rewriting it.
+        #[clap(long)]
+        backup: bool,
+
+        /// Rewrite the store even if it is already current.
+        #[clap(long)]
+        force: bool,
+    },
 }
 
 fn main() {
-    let token_store = match TokenStore::new(STORE_FILE_PATH.to_string()) {
+    let args = Cli::parse();
+    // version needs no store, so answer it before touching the file
+    if let Commands::Version { json } = args.command {
+        print_version(json);
+        return;
+    }
+    let store_key = match read_key(args.store_key_file.as_ref(), STORE_KEY_ENV) {
+        Ok(key) => key,
+        Err(err) => {
+            println!("Failed to read store key: {}", err);
+            return;
+        }
+    };
+    let signing_key =
+        match read_key_material(args.hmac_key_file.as_ref(), HMAC_KEY_ENV).and_then(|secret| {
+            secret
+                .map(|secret| SigningKey::new(secret.as_bytes()))
+                .transpose()
+        }) {
+            Ok(key) => key,
+            Err(err) => {
+                println!("Failed to read HMAC key: {}", err);
+                return;
+            }
+        };
+    let remote_authorization =
+        match read_key_material(args.store_auth_file.as_ref(), STORE_AUTH_ENV).and_then(|value| {
+            value
+                .map(|value| parse_header_value(&value).map_err(|e| anyhow!(e)))
+                .transpose()
+        }) {
+            Ok(authorization) => authorization,
+            Err(err) => {
+                println!("Failed to read store authorization: {}", err);
+                return;
+            }
+        };
+    let store_options = StoreOptions {
+        key: store_key,
+        format: args.store_format,
+        max_label_len: args.max_label_len,
+        max_secret_len: args.max_secret_len,
+        signing_key,
+        force_overwrite: args.force_store,
+        remote_authorization,
+        secret_generator: Arc::new(RandomGenerator::new(args.secret_format, args.secret_bytes)),
+        hash_secrets: args.hash_secrets,
+        ..StoreOptions::default()
+    };
+    let mut token_store = match TokenStore::new(args.store, store_options) {
         Ok(store) => store,
-        Err(_) => {
-            println!("Failed to instantiate token store");
+        Err(err) => {
+            println!("Failed to instantiate token store: {}", err);
             return;
         }
     };
-    let args = Cli::parse();
     match args.command {
-        Commands::Serve { host } => match host {
+        Commands::Serve {
+            host,
+            max_request_line,
+            slow_threshold_ms,
+            tcp_nodelay,
+            tcp_keepalive,
+            dry_run,
+            workers,
+            bind_retry,
+            bind_retry_delay,
+            log_file,
+            log_format,
+            trusted_proxy,
+            max_request_time,
+            read_timeout,
+            write_timeout,
+            ok_body,
+            unauthorized_body,
+            body_content_type,
+            once,
+            allow_empty_store,
+            keep_revoked_for,
+            prune_expired_on_load,
+            port_file,
+            guard_path,
+            introspection,
+            health_path,
+            admin_reload,
+            admin_token_file,
+            label_header,
+            forward_auth,
+            accept_basic,
+            fail_open,
+            fail_closed,
+            reload_interval,
+            pid_file,
+            force,
+            server_header,
+            no_server_header: _,
+        } => match host {
             Some(host) => {
+                if let Some(keep_revoked_for) = keep_revoked_for {
+                    compact_store(&mut token_store, keep_revoked_for);
+                }
+                if prune_expired_on_load {
+                    prune_expired(&mut token_store);
+                }
+                // a server that turns everyone away is more likely a missing volume than intended
+                if token_store.is_loaded() && token_store.is_empty() && !allow_empty_store {
+                    println!(
+                        "Token store at {} holds no tokens, refusing to serve. Pass --allow-empty-store to serve anyway.",
+                        token_store.file_path().display()
+                    );
+                    std::process::exit(1);
+                }
+                if let Some(log_file) = log_file {
+                    if let Err(err) = logging::log_to_file(log_file) {
+                        println!("Failed to set up logging: {}", err);
+                        return;
+                    }
+                }
+                let load_template = |template: Option<String>| {
+                    template
+                        .map(|template| {
+                            ResponseTemplate::load(&template, body_content_type.as_deref())
+                        })
+                        .transpose()
+                };
+                let (ok_body, unauthorised_body) =
+                    match (load_template(ok_body), load_template(unauthorized_body)) {
+                        (Ok(ok_body), Ok(unauthorised_body)) => (ok_body, unauthorised_body),
+                        (Err(err), _) | (_, Err(err)) => {
+                            println!("Failed to load response template: {}", err);
+                            return;
+                        }
+                    };
+                let admin_token = match admin_reload
+                    .then(|| read_key_material(admin_token_file.as_ref(), ADMIN_TOKEN_ENV))
+                    .transpose()
+                    .map(Option::flatten)
+                {
+                    Ok(Some(admin_token)) if admin_token.is_empty() => {
+                        println!("Failed to read admin token: the token is empty");
+                        std::process::exit(1);
+                    }
+                    Ok(None) if admin_reload => {
+                        println!(
+                            "No admin token supplied, use --admin-token-file or {}",
+                            ADMIN_TOKEN_ENV
+                        );
+                        std::process::exit(1);
+                    }
+                    Ok(admin_token) => admin_token,
+                    Err(err) => {
+                        println!("Failed to read admin token: {}", err);
+                        std::process::exit(1);
+                    }
+                };
+                let options = ServerOptions {
+                    max_request_line,
+                    slow_threshold: slow_threshold_ms.map(Duration::from_millis),
+                    tcp_nodelay,
+                    tcp_keepalive: tcp_keepalive.map(Duration::from_secs),
+                    dry_run,
+                    bind_retries: bind_retry,
+                    bind_retry_delay: Duration::from_millis(bind_retry_delay),
+                    max_request_time: max_request_time.map(Duration::from_secs),
+                    read_timeout: Duration::from_secs(read_timeout),
+                    write_timeout: Duration::from_secs(write_timeout),
+                    ok_body,
+                    unauthorised_body,
+                    once,
+                    port_file,
+                    fail_open: fail_open && !fail_closed,
+                    server_header: server_header
+                        .map(|value| value.unwrap_or_else(|| format!("mellon/{}", VERSION))),
+                    reload_interval: (reload_interval > 0)
+                        .then(|| Duration::from_secs(reload_interval)),
+                    log_format,
+                    trusted_proxies: trusted_proxy,
+                    guard_path,
+                    introspection,
+                    accept_basic,
+                    health_path,
+                    label_header,
+                    admin_token,
+                    forward_auth,
+                    workers: workers.map_or_else(default_workers, usize::from),
+                };
+                let pid_file = match pid_file
+                    .map(|path| PidFile::create(path, force))
+                    .transpose()
+                {
+                    Ok(pid_file) => pid_file,
+                    Err(err) => {
+                        println!("Failed to write PID file: {}", err);
+                        std::process::exit(1);
+                    }
+                };
+                log_effective_config(&host, &options, &token_store);
                 println!("Server starting up on {}", host);
-                match MellonServer::serve(host, token_store) {
+                let served = MellonServer::serve(host, token_store, options);
+                // exiting skips destructors, so the PID file has to go first
+                drop(pid_file);
+                match served {
+                    Ok(status) if once => std::process::exit(once_exit_code(status)),
                     Ok(_) => println!("Server shut down!"),
                     Err(err) => println!("Failed to host server: {}", err),
                 }
@@ -79,59 +795,958 @@ fn main() {
             None => println!("Host is not defined properly!"),
         },
         Commands::Token { action } => match action {
-            TokenCommands::Add { token_label } => add_token(token_store, token_label),
-            TokenCommands::Rescind { token_label } => rescind_token(token_store, token_label),
-            TokenCommands::List {} => list_tokens(token_store),
+            TokenCommands::Add {
+                token_label,
+                batch,
+                annotate,
+                max_uses,
+                scope,
+                ttl,
+                expires_at,
+                copy,
+                json,
+                secret,
+                matching,
+            } => {
+                let now = token_store.now().unwrap_or_default();
+                let options = TokenOptions {
+                    annotations: annotate.into_iter().collect(),
+                    max_uses,
+                    scopes: scope,
+                    expires_at: expires_at.or(ttl.map(|ttl| now.saturating_add(ttl))),
+                    matching,
+                };
+                if batch {
+                    return add_token_batch(token_store, options);
+                }
+                let token_label = match token_label.map_or_else(read_label, Ok) {
+                    Ok(token_label) => token_label,
+                    Err(err) => {
+                        println!("Failed to read label from stdin: {}", err);
+                        return;
+                    }
+                };
+                add_token(token_store, token_label, secret, options, copy, json)
+            }
+            TokenCommands::Rotate {
+                token_label,
+                copy,
+                json,
+            } => rotate_token(token_store, token_label, copy, json),
+            TokenCommands::Rescind {
+                token_label,
+                by_secret: _,
+                by_token,
+                keep_tombstones,
+            } => match (token_label, by_token) {
+                (Some(token_label), _) => rescind_token(token_store, token_label, keep_tombstones),
+                (None, Some(secret)) => rescind_by_secret(token_store, &secret, keep_tombstones),
+                (None, None) => {
+                    let mut secret = String::new();
+                    if let Err(err) = io::stdin().read_line(&mut secret) {
+                        println!("Failed to read secret from stdin: {}", err);
+                        std::process::exit(2);
+                    }
+                    let secret = secret.trim_end_matches(['\r', '\n']);
+                    rescind_by_secret(token_store, secret, keep_tombstones)
+                }
+            },
+            TokenCommands::List {
+                include_revoked,
+                wide,
+                no_truncate,
+                format,
+                stream,
+                expired,
+                active,
+                expiring_within,
+                sort,
+            } => {
+                let filter = match (expired, active, expiring_within) {
+                    (true, _, _) => Some(ExpiryFilter::Expired),
+                    (_, true, _) => Some(ExpiryFilter::Active),
+                    (_, _, Some(secs)) => Some(ExpiryFilter::ExpiringWithin(secs)),
+                    _ => None,
+                };
+                let listing = Listing {
+                    include_revoked,
+                    wide,
+                    truncate: !wide && !no_truncate,
+                    format,
+                    filter,
+                    sort,
+                };
+                match stream {
+                    true => stream_tokens(token_store, listing),
+                    false => list_tokens(token_store, listing),
+                }
+            }
+            TokenCommands::Inspect { token_label } => inspect_token(token_store, token_label),
+            TokenCommands::Whoami => whoami(token_store),
+            TokenCommands::Diff {
+                desired_file,
+                apply,
+            } => diff_tokens(token_store, desired_file, apply),
+            TokenCommands::ImportEnv { env_file, replace } => {
+                import_env_tokens(token_store, env_file, replace)
+            }
+        },
+        Commands::Store { action } => match action {
+            StoreCommands::Rekey { new_key_file } => rekey_store(token_store, new_key_file),
+            StoreCommands::Compact { keep_revoked_for } => {
+                compact_store(&mut token_store, keep_revoked_for)
+            }
+            StoreCommands::Fsck { fix } => fsck_store(token_store, fix),
+            StoreCommands::Migrate { backup, force } => migrate_store(token_store, backup, force),
         },
+        Commands::Version { .. } => unreachable!("handled before the store is loaded"),
+    }
+}
+
+/// Logs the settings the server ends up running with, as a single JSON line.
+/// Anything secret is only reported as being present.
+fn log_effective_config(host: &str, options: &ServerOptions, token_store: &TokenStore) {
+    let millis = |duration: Option<Duration>| duration.map(|duration| duration.as_millis() as u64);
+    let config = serde_json::json!({
+        "host": host,
+        "store_path": token_store.file_path(),
+        "store_format": token_store.format(),
+        "store_key": token_store.is_encrypted().then_some("<redacted>"),
+        "hmac_key": token_store.is_signing().then_some("<redacted>"),
+        "max_request_line": options.max_request_line,
+        "max_request_time_ms": millis(options.max_request_time),
+        "read_timeout_ms": options.read_timeout.as_millis() as u64,
+        "write_timeout_ms": options.write_timeout.as_millis() as u64,
+        "slow_threshold_ms": millis(options.slow_threshold),
+        "tcp_nodelay": options.tcp_nodelay,
+        "tcp_keepalive_ms": millis(options.tcp_keepalive),
+        "dry_run": options.dry_run,
+        "workers": options.workers,
+        "bind_retries": options.bind_retries,
+        "bind_retry_delay_ms": options.bind_retry_delay.as_millis() as u64,
+        "ok_body": options.ok_body.as_ref().map(ResponseTemplate::content_type),
+        "fail_open": options.fail_open,
+        "once": options.once,
+        "port_file": options.port_file,
+        "guard_path": options.guard_path,
+        "introspection": options.introspection,
+        "accept_basic": options.accept_basic,
+        "health_path": options.health_path,
+        "label_header": options.label_header,
+        "admin_reload": options.admin_token.is_some(),
+        "forward_auth": options.forward_auth,
+        "reload_interval_ms": millis(options.reload_interval),
+        "server_header": options.server_header,
+        "log_format": options.log_format,
+        "trusted_proxies": options.trusted_proxies.iter().map(Cidr::to_string).collect::<Vec<_>>(),
+        "unauthorised_body": options.unauthorised_body.as_ref().map(ResponseTemplate::content_type),
+    });
+    mellon::log!("Effective configuration: {}", config);
+}
+
+/// Exit code for `serve --once`, so that shell tests can assert on the outcome.
+fn once_exit_code(status: Option<u16>) -> i32 {
+    match status {
+        Some(200) => 0,
+        Some(401 | 403) => 1,
+        _ => 2,
+    }
+}
+
+fn print_version(json: bool) {
+    if json {
+        println!(
+            r#"{{"version":"{}","commit":"{}","build_timestamp":{}}}"#,
+            VERSION, GIT_COMMIT, BUILD_TIMESTAMP
+        );
+    } else {
+        println!("mellon {}", VERSION);
+        println!("commit: {}", GIT_COMMIT);
+        println!("built: {}", BUILD_TIMESTAMP);
+    }
+}
+
+/// Reads key material from the given file, falling back to an environment variable.
+fn read_key(key_file: Option<&PathBuf>, env_var: &str) -> Result<Option<StoreKey>> {
+    match read_key_material(key_file, env_var)? {
+        Some(secret) => StoreKey::derive(secret.as_bytes()).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Reads key material from the file given, falling back to the environment.
+fn read_key_material(key_file: Option<&PathBuf>, env_var: &str) -> Result<Option<String>> {
+    let secret = match key_file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| anyhow!("Unable to read key file {}: {}", path.display(), e))?,
+        None => match env::var(env_var) {
+            Ok(secret) => secret,
+            Err(env::VarError::NotPresent) => return Ok(None),
+            Err(e) => return Err(anyhow!("Unable to read {}: {}", env_var, e)),
+        },
+    };
+    Ok(Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn rekey_store(mut token_store: TokenStore, new_key_file: Option<PathBuf>) {
+    let new_key = match read_key(new_key_file.as_ref(), NEW_STORE_KEY_ENV) {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            println!(
+                "No new key supplied, use --new-key-file or {}",
+                NEW_STORE_KEY_ENV
+            );
+            return;
+        }
+        Err(err) => {
+            println!("Failed to read new store key: {}", err);
+            return;
+        }
+    };
+    match token_store.rekey(Some(new_key)) {
+        Ok(_) => println!("Token store has been re-encrypted with the new key."),
+        Err(err) => println!("Failed to rekey token store: {}", err),
+    }
+}
+
+fn compact_store(token_store: &mut TokenStore, keep_revoked_for: u64) {
+    let revoked_before = token_store
+        .now()
+        .unwrap_or_default()
+        .saturating_sub(keep_revoked_for);
+    match token_store.compact(revoked_before) {
+        Ok(compacted) => println!(
+            "Compacted {} revoked entries from the token store.",
+            compacted
+        ),
+        Err(err) => println!("Failed to compact token store: {}", err),
     }
 }
 
-fn rescind_token(mut token_store: TokenStore, label: String) {
-    let result = token_store.rescind(label.as_str());
+fn prune_expired(token_store: &mut TokenStore) {
+    match token_store.prune_expired() {
+        Ok(pruned) => println!("Pruned {} expired tokens from the token store.", pruned),
+        Err(err) => println!("Failed to prune expired tokens: {}", err),
+    }
+}
+
+fn migrate_store(mut token_store: TokenStore, backup: bool, force: bool) {
+    let layout = match token_store.layout() {
+        Ok(layout) => layout,
+        Err(err) => {
+            println!("Failed to read token store: {}", err);
+            return;
+        }
+    };
+    match token_store.is_current() {
+        Ok(true) if !force => {
+            println!(
+                "Token store is already {}, pass --force to rewrite it anyway.",
+                describe_layout(layout)
+            );
+            return;
+        }
+        Ok(_) => {}
+        Err(err) => {
+            println!("Failed to read token store: {}", err);
+            return;
+        }
+    }
+    if backup {
+        let backup_path = PathBuf::from(format!(
+            "{}.{}.bak",
+            token_store.file_path().display(),
+            token_store.now().unwrap_or_default()
+        ));
+        if let Err(err) = fs::copy(token_store.file_path(), &backup_path) {
+            println!("Failed to back up token store: {}", err);
+            return;
+        }
+        println!("Backed up token store to {}", backup_path.display());
+    }
+    if let Err(err) = token_store.migrate() {
+        println!("Failed to migrate token store: {}", err);
+        return;
+    }
+    let tokens = token_store.iter().map(Iterator::count).unwrap_or_default();
+    let current = token_store.layout().ok().map(describe_layout);
+    println!(
+        "Migrated {} tokens and {} tombstones from {} to {}.",
+        tokens,
+        token_store.tombstones().count(),
+        describe_layout(layout),
+        current.as_deref().unwrap_or("the current layout")
+    );
+}
+
+fn describe_layout(layout: StoreLayout) -> String {
+    let format = match layout.format {
+        Some(StoreFormat::Colon) => "colon",
+        Some(StoreFormat::Jsonl) => "jsonl",
+        None => return "an empty store".to_string(),
+    };
+    match layout.marked {
+        true => format!("a marked {} store", format),
+        false => format!("an unmarked {} store", format),
+    }
+}
+
+fn fsck_store(mut token_store: TokenStore, fix: bool) {
+    let check = match StoreCheck::run(&token_store) {
+        Ok(check) => check,
+        Err(err) => {
+            println!("Failed to check token store: {}", err);
+            return;
+        }
+    };
+    if check.is_clean() {
+        println!("No problems found in {}", token_store.file_path().display());
+        return;
+    }
+    for finding in check.findings.iter() {
+        println!("{}", finding);
+    }
+    if !fix {
+        println!("Run again with --fix to repair the store.");
+        return;
+    }
+    match check.fix(&mut token_store) {
+        Ok(_) => println!("Token store has been repaired."),
+        Err(err) => println!("Failed to repair token store: {}", err),
+    }
+}
+
+fn rescind_token(mut token_store: TokenStore, label: String, keep_tombstone: bool) {
+    let result = match keep_tombstone {
+        true => token_store.rescind_with_tombstone(label.as_str()),
+        false => token_store.rescind(label.as_str()),
+    };
     match result {
         Ok(_) => println!(
-            "Token with label {} has been removed. Be sure to restart server to load changes!",
+            "Token with label {} has been removed. Send the server SIGHUP or restart it to load changes!",
             label
         ),
         Err(err) => println!("Failed to rescind token: {}", err),
     }
 }
 
-fn add_token(mut token_store: TokenStore, label: String) {
-    let new_token = token_store.create(label.as_str());
-    let new_token = match new_token {
-        Ok(uuid) => uuid,
+/// Rescinds the token a secret belongs to, exiting with 1 when no token
+/// but a pattern matches it.
+fn rescind_by_secret(token_store: TokenStore, secret: &str, keep_tombstone: bool) {
+    let label = match token_store.owner_of(secret) {
+        Ok(label) => label,
+        Err(err @ (TokenStoreError::UnknownSecret | TokenStoreError::PatternOnly(_))) => {
+            println!("Failed to rescind token: {}", err);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            println!("Failed to look up token: {}", err);
+            std::process::exit(2);
+        }
+    };
+    rescind_token(token_store, label, keep_tombstone)
+}
+
+fn parse_header_value(value: &str) -> Result<String, String> {
+    match value.contains(['\r', '\n']) {
+        true => Err("header values must not contain line breaks".to_string()),
+        false => Ok(value.to_string()),
+    }
+}
+
+fn parse_secret_bytes(bytes: &str) -> Result<usize, String> {
+    match bytes.parse::<usize>() {
+        Ok(bytes) if (MIN_SECRET_BYTES..=MAX_SECRET_BYTES).contains(&bytes) => Ok(bytes),
+        _ => Err(format!(
+            "secrets must be drawn from {} to {} random bytes",
+            MIN_SECRET_BYTES, MAX_SECRET_BYTES
+        )),
+    }
+}
+
+fn parse_annotation(annotation: &str) -> Result<(String, String), String> {
+    match annotation.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("annotations must be given as KEY=VALUE".to_string()),
+    }
+}
+
+fn parse_scope(scope: &str) -> Result<Scope, String> {
+    scope.parse().map_err(|e: anyhow::Error| e.to_string())
+}
+
+/// Parses an RFC3339 timestamp into seconds since the epoch, refusing any
+/// that have already passed.
+fn parse_expires_at(timestamp: &str) -> Result<u64, String> {
+    let expires_at = OffsetDateTime::parse(timestamp, &Rfc3339)
+        .map_err(|e| format!("expected an RFC3339 timestamp: {}", e))?
+        .unix_timestamp();
+    let now = epoch_now().unwrap_or_default();
+    match u64::try_from(expires_at) {
+        Ok(expires_at) if expires_at > now => Ok(expires_at),
+        _ => Err("timestamp is in the past".to_string()),
+    }
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+        .and_then(|timestamp| timestamp.format(&Rfc3339).ok())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn format_created(created_at: Option<u64>) -> String {
+    match created_at {
+        Some(created_at) => format_timestamp(created_at),
+        None => "unknown".to_string(),
+    }
+}
+
+fn format_last_used(last_used_at: Option<u64>) -> String {
+    match last_used_at {
+        Some(last_used_at) => format_timestamp(last_used_at),
+        None => "never".to_string(),
+    }
+}
+
+fn format_expiry(expires_at: Option<u64>) -> String {
+    match expires_at {
+        Some(expires_at) => format_timestamp(expires_at),
+        None => "never".to_string(),
+    }
+}
+
+fn add_token(
+    mut token_store: TokenStore,
+    label: String,
+    secret: Option<String>,
+    options: TokenOptions,
+    copy: bool,
+    json: bool,
+) {
+    let new_token = match secret {
+        Some(secret) => token_store.create_with_secret(label.as_str(), &secret, options),
+        None => token_store.create(label.as_str(), options),
+    };
+    let new_token = new_token.and_then(|token| match token.matching {
+        MatchKind::Signed => token_store.sign(&token.label).map(|secret| (token, secret)),
+        _ => Ok((token.clone(), token.secret)),
+    });
+    let (token, secret) = match new_token {
+        Ok(new_token) => new_token,
+        Err(error) if json => {
+            eprintln!("{}", serde_json::json!({ "error": error.to_string() }));
+            std::process::exit(1);
+        }
         Err(error) => {
             println!("Failed to generate new token for label: {}", error);
             return;
         }
     };
-    println!("{}", new_token.1);
+    print_secret(&token, &secret, copy, json)
+}
+
+fn rotate_token(mut token_store: TokenStore, label: String, copy: bool, json: bool) {
+    let token = match token_store.rotate(&label) {
+        Ok(token) => token,
+        Err(error) if json => {
+            eprintln!("{}", serde_json::json!({ "error": error.to_string() }));
+            std::process::exit(1);
+        }
+        Err(error) => {
+            println!("Failed to rotate token: {}", error);
+            return;
+        }
+    };
+    print_secret(&token, &token.secret, copy, json)
+}
+
+/// Hands a new secret to the operator, the one time it is shown.
+fn print_secret(token: &Token, secret: &str, copy: bool, json: bool) {
+    if json {
+        let output = serde_json::json!({
+            "label": token.label,
+            "secret": secret,
+            "expires_at": token.expires_at.map(format_timestamp),
+        });
+        println!("{}", output);
+        return;
+    }
+    if copy {
+        match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(secret)) {
+            Ok(_) => {
+                println!(
+                    "Secret for {} has been copied to the clipboard.",
+                    token.label
+                );
+                return;
+            }
+            // headless machines have no clipboard, the secret must not be lost
+            Err(err) => eprintln!("Unable to copy to the clipboard, printing instead: {}", err),
+        }
+    }
+    println!("{}", secret);
+}
+
+fn read_label() -> Result<String> {
+    let mut label = String::new();
+    io::stdin().read_line(&mut label)?;
+    match label.trim() {
+        "" => Err(anyhow!("no label given")),
+        label => Ok(label.to_string()),
+    }
+}
+
+/// Adds a token with a generated secret for every label on stdin. Output is
+/// meant for piping on, so anything but the new tokens goes to stderr.
+fn add_token_batch(mut token_store: TokenStore, options: TokenOptions) {
+    let labels: Vec<String> = match io::stdin().lines().collect::<io::Result<Vec<_>>>() {
+        Ok(lines) => lines
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(err) => {
+            eprintln!("Failed to read labels from stdin: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let created = match token_store.create_batch(&labels, &options) {
+        Ok(created) => created,
+        Err(err) => {
+            eprintln!("Failed to generate new tokens: {}", err);
+            std::process::exit(1);
+        }
+    };
+    for (label, token) in labels.iter().zip(created) {
+        let secret = token.and_then(|token| match token.matching {
+            MatchKind::Signed => token_store.sign(&token.label),
+            _ => Ok(token.secret),
+        });
+        match secret {
+            Ok(secret) => println!("{}\t{}", label, secret),
+            Err(err) => eprintln!("Skipping {}: {}", label, err),
+        }
+    }
+}
+
+/// Tells which label a token belongs to. The token is read from stdin so
+/// that it stays out of the shell history, and is never printed.
+fn whoami(token_store: TokenStore) {
+    let mut token_string = String::new();
+    if let Err(err) = io::stdin().read_line(&mut token_string) {
+        println!("Failed to read token from stdin: {}", err);
+        std::process::exit(2);
+    }
+    let token_string = token_string.trim_end_matches(['\r', '\n']);
+    match token_store.authenticate(token_string) {
+        Ok(Some(token)) => println!("{}", token.label),
+        Ok(None) => {
+            println!("no match");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            println!("Failed to look up token: {}", err);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn inspect_token(token_store: TokenStore, label: String) {
+    match token_store.get(label.as_str()) {
+        Ok(Some(token)) => {
+            let mut table = Table::new();
+            table.add_row(row!["Label", token.label]);
+            table.add_row(row!["Token", display_secret(token)]);
+            table.add_row(row![
+                "Uses left",
+                format_remaining_uses(token.remaining_uses)
+            ]);
+            let scopes = match token.scopes.is_empty() {
+                true => "any".to_string(),
+                false => token
+                    .scopes
+                    .iter()
+                    .map(Scope::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            };
+            table.add_row(row!["Scopes", scopes]);
+            table.add_row(row!["Expires", format_expiry(token.expires_at)]);
+            table.add_row(row!["Created", format_created(token.created_at)]);
+            table.add_row(row!["Last used", format_last_used(token.last_used_at)]);
+            for (key, value) in token.annotations.iter() {
+                table.add_row(row![key, value]);
+            }
+            table.printstd();
+        }
+        Ok(None) => println!("No token with label {}", label),
+        Err(err) => println!("Unable to inspect token: {}", err),
+    }
+}
+
+fn diff_tokens(mut token_store: TokenStore, desired_file: PathBuf, apply: bool) {
+    let desired = match fs::read_to_string(&desired_file)
+        .map_err(|e| anyhow!("Unable to read {}: {}", desired_file.display(), e))
+        .and_then(|contents| DesiredToken::parse_all(&contents))
+    {
+        Ok(desired) => desired,
+        Err(err) => {
+            println!("Failed to load desired tokens: {}", err);
+            return;
+        }
+    };
+    let diff = match StoreDiff::between(&token_store, desired) {
+        Ok(diff) => diff,
+        Err(err) => {
+            println!("Failed to compare tokens: {}", err);
+            return;
+        }
+    };
+    for token in diff.to_add.iter() {
+        println!("+ {}", token.label);
+    }
+    for token in diff.to_update.iter() {
+        println!("~ {}", token.label);
+    }
+    for label in diff.to_rescind.iter() {
+        println!("- {}", label);
+    }
+    for label in diff.unchanged.iter() {
+        println!("= {}", label);
+    }
+    if !apply || diff.is_empty() {
+        return;
+    }
+    match diff.apply(&mut token_store) {
+        Ok(generated) => {
+            for (label, secret) in generated {
+                println!("{}\t{}", label, secret);
+            }
+        }
+        Err(err) => println!("Failed to apply changes: {}", err),
+    }
+}
+
+fn import_env_tokens(mut token_store: TokenStore, env_file: PathBuf, replace: bool) {
+    let imported = match fs::read_to_string(&env_file)
+        .map_err(|e| anyhow!("Unable to read {}: {}", env_file.display(), e))
+        .and_then(|contents| EnvToken::parse_all(&contents))
+    {
+        Ok(imported) => imported,
+        Err(err) => {
+            println!("Failed to load {}: {}", env_file.display(), err);
+            return;
+        }
+    };
+    let import = match StoreImport::plan(&token_store, imported, replace) {
+        Ok(import) => import,
+        Err(err) => {
+            println!("Failed to import tokens: {}", err);
+            return;
+        }
+    };
+    for (label, reason) in import.skipped.iter() {
+        println!("Skipping {}: {}", label, reason);
+    }
+    let summary = format!(
+        "{} created, {} updated, {} skipped",
+        import.created.len(),
+        import.updated.len(),
+        import.skipped.len()
+    );
+    if import.is_empty() {
+        println!("{}", summary);
+        return;
+    }
+    match import.apply(&mut token_store) {
+        Ok(_) => println!(
+            "{}. Send the server SIGHUP or restart it to load changes!",
+            summary
+        ),
+        Err(err) => println!("Failed to import tokens: {}", err),
+    }
+}
+
+/// The secret as listed, hashed secrets have nothing left worth showing.
+fn display_secret(token: &Token) -> String {
+    match token.matching {
+        MatchKind::Hashed => "(hashed)".to_string(),
+        _ => mask_secret(&token.secret),
+    }
+}
+
+/// Hides all but the last four characters of a secret.
+fn mask_secret(secret: &str) -> String {
+    // counted in characters, a byte offset may land inside one
+    let hidden = secret.chars().count().saturating_sub(4);
+    "*".repeat(hidden) + &secret.chars().skip(hidden).collect::<String>()
+}
+
+fn format_annotations(annotations: &BTreeMap<String, String>) -> String {
+    annotations
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_remaining_uses(remaining_uses: Option<u64>) -> String {
+    match remaining_uses {
+        Some(uses) => uses.to_string(),
+        None => "unlimited".to_string(),
+    }
+}
+
+/// The columns `list` prints, in order.
+fn list_header(wide: bool) -> Vec<&'static str> {
+    let mut header = vec![
+        "Label",
+        "Token",
+        "Uses left",
+        "Expires",
+        "Created",
+        "Last used",
+    ];
+    if wide {
+        header.push("Annotations");
+    }
+    header
+}
+
+fn token_row(token: &Token, wide: bool) -> Vec<String> {
+    let mut row = vec![
+        token.label.clone(),
+        display_secret(token),
+        format_remaining_uses(token.remaining_uses),
+        format_expiry(token.expires_at),
+        format_created(token.created_at),
+        format_last_used(token.last_used_at),
+    ];
+    if wide {
+        row.push(format_annotations(&token.annotations));
+    }
+    row
+}
+
+fn tombstone_row(tombstone: &Tombstone, wide: bool) -> Vec<String> {
+    let revoked = match tombstone.revoked_at {
+        Some(revoked_at) => format!("revoked at {}", format_timestamp(revoked_at)),
+        None => "revoked".to_string(),
+    };
+    let mut row = vec![
+        tombstone.label.clone(),
+        revoked,
+        String::new(),
+        String::new(),
+        String::new(),
+        String::new(),
+    ];
+    if wide {
+        row.push(String::new());
+    }
+    row
+}
+
+fn token_json(token: &Token) -> serde_json::Value {
+    serde_json::json!({
+        "label": token.label,
+        "token": display_secret(token),
+        "remaining_uses": token.remaining_uses,
+        "expires_at": token.expires_at.map(format_timestamp),
+        "created_at": token.created_at.map(format_timestamp),
+        "last_used_at": token.last_used_at.map(format_timestamp),
+        "annotations": token.annotations,
+    })
+}
+
+fn tombstone_json(tombstone: &Tombstone) -> serde_json::Value {
+    serde_json::json!({
+        "label": tombstone.label,
+        "revoked_at": tombstone.revoked_at.map(format_timestamp),
+    })
 }
 
-fn list_tokens(token_store: TokenStore) {
-    match token_store.iter() {
-        Ok(iter) => {
+fn list_tokens(token_store: TokenStore, listing: Listing) {
+    let iter = match listing.tokens(&token_store) {
+        Ok(iter) => iter,
+        Err(err) => {
+            println!("Unable to list tokens: {}", err);
+            return;
+        }
+    };
+    let tombstones = token_store.tombstones().filter(|_| listing.include_revoked);
+    match listing.format {
+        ListFormat::Table => {
+            let header = list_header(listing.wide);
+            let mut rows: Vec<_> = iter
+                .map(|token| token_row(token, listing.wide))
+                .chain(tombstones.map(|tombstone| tombstone_row(tombstone, listing.wide)))
+                .collect();
+            let width = terminal_width().filter(|_| listing.truncate);
+            if width.is_some_and(|width| !fit_to_width(&header, &mut rows, width)) {
+                print_vertical(&header, &rows);
+                return;
+            }
             let mut table = Table::new();
-            table.add_row(row!["Label", "Token"]);
-            for token in iter {
-                table.add_row(Row::new(vec![
-                    Cell::new(token.0.as_str()),
-                    Cell::new(
-                        ("*".repeat(token.1.len().saturating_sub(4))
-                            + &token.1[token.1.len().saturating_sub(4)..])
-                            .as_str(),
-                    ),
-                ]));
+            table.add_row(Row::new(header.into_iter().map(Cell::new).collect()));
+            for row in rows {
+                table.add_row(Row::new(row.iter().map(|cell| Cell::new(cell)).collect()));
             }
             table.printstd();
         }
-        Err(err) => println!("Unable to list tokens: {}", err),
+        ListFormat::Json => {
+            let entries: Vec<_> = iter
+                .map(token_json)
+                .chain(tombstones.map(tombstone_json))
+                .collect();
+            println!("{}", serde_json::Value::Array(entries));
+        }
+    }
+}
+
+/// Labels are never shortened below this many characters to fit.
+const MIN_LABEL_WIDTH: usize = 12;
+
+/// Width of the terminal stdout goes to, none when it is not a terminal so
+/// that piped output is never shortened.
+fn terminal_width() -> Option<usize> {
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+    if let Some(columns) = env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+        return Some(columns);
+    }
+    window_columns()
+}
+
+#[cfg(unix)]
+fn window_columns() -> Option<usize> {
+    // SAFETY: winsize is plain data, and TIOCGWINSZ only writes into it.
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_col > 0).then_some(size.ws_col as usize)
+}
+
+#[cfg(not(unix))]
+fn window_columns() -> Option<usize> {
+    None
+}
+
+/// Shortens the labels of the rows until the table fits in `width`,
+/// returning false when even the shortest labels would not.
+fn fit_to_width(header: &[&str], rows: &mut [Vec<String>], width: usize) -> bool {
+    let column_width = |column: usize, rows: &[Vec<String>]| {
+        rows.iter()
+            .map(|row| row[column].chars().count())
+            .chain([header[column].chars().count()])
+            .max()
+            .unwrap_or_default()
+    };
+    // every column is padded by a space either side and followed by a border
+    let table_width = |rows: &[Vec<String>]| {
+        1 + (0..header.len())
+            .map(|column| column_width(column, rows) + 3)
+            .sum::<usize>()
+    };
+    let overflow = table_width(rows).saturating_sub(width);
+    if overflow == 0 {
+        return true;
+    }
+    let label_width = column_width(0, rows);
+    let Some(fitted) = label_width
+        .checked_sub(overflow)
+        .filter(|fitted| *fitted >= MIN_LABEL_WIDTH)
+    else {
+        return false;
+    };
+    for row in rows.iter_mut() {
+        if row[0].chars().count() > fitted {
+            row[0] = row[0].chars().take(fitted - 1).chain(['…']).collect();
+        }
+    }
+    true
+}
+
+/// Lays the rows out one field per line, for terminals too narrow for a table.
+fn print_vertical(header: &[&str], rows: &[Vec<String>]) {
+    let name_width = header
+        .iter()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or_default();
+    for (index, row) in rows.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        for (name, value) in header.iter().zip(row) {
+            println!("{:>width$}: {}", name, value, width = name_width);
+        }
     }
 }
 
+/// Writes the listing out a row at a time, so that nothing beyond the
+/// store itself is held in memory however many tokens there are.
+fn stream_tokens(token_store: TokenStore, listing: Listing) {
+    let iter = match listing.tokens(&token_store) {
+        Ok(iter) => iter,
+        Err(err) => {
+            println!("Unable to list tokens: {}", err);
+            return;
+        }
+    };
+    let tombstones = token_store.tombstones().filter(|_| listing.include_revoked);
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let result = match listing.format {
+        ListFormat::Table => {
+            let rows = iter
+                .map(|token| token_row(token, listing.wide))
+                .chain(tombstones.map(|tombstone| tombstone_row(tombstone, listing.wide)));
+            writeln!(out, "{}", list_header(listing.wide).join("\t")).and_then(|_| {
+                rows.into_iter()
+                    .try_for_each(|row| writeln!(out, "{}", row.join("\t")))
+            })
+        }
+        ListFormat::Json => {
+            let entries = iter.map(token_json).chain(tombstones.map(tombstone_json));
+            write!(out, "[")
+                .and_then(|_| {
+                    entries.enumerate().try_for_each(|(index, entry)| {
+                        let separator = if index == 0 { "" } else { "," };
+                        write!(out, "{}{}", separator, entry)
+                    })
+                })
+                .and_then(|_| writeln!(out, "]"))
+        }
+    };
+    if let Err(err) = result.and_then(|_| out.flush()) {
+        eprintln!("Unable to list tokens: {}", err);
+    }
+}
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const GIT_COMMIT: &str = env!("MELLON_GIT_COMMIT");
+
+const BUILD_TIMESTAMP: &str = env!("MELLON_BUILD_TIMESTAMP");
+
 const STORE_FILE_PATH: &str = "/tmp/mellon/tokens";
 
+const STORE_KEY_ENV: &str = "MELLON_STORE_KEY";
+
+const NEW_STORE_KEY_ENV: &str = "MELLON_NEW_STORE_KEY";
+
+const HMAC_KEY_ENV: &str = "MELLON_HMAC_KEY";
+const STORE_AUTH_ENV: &str = "MELLON_STORE_AUTH";
+const ADMIN_TOKEN_ENV: &str = "MELLON_ADMIN_TOKEN";
+
+/// Bounds on --secret-bytes, fewer is guessable and more outgrows the
+/// default longest secret once written out as hex.
+const MIN_SECRET_BYTES: usize = 16;
+const MAX_SECRET_BYTES: usize = 512;
+
 const THE_DOORS_OF_DURIN: &str = r#"
 
              _,-'_,-----------._`-._    
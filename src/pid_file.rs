@@ -0,0 +1,64 @@
+use std::{fs, io::ErrorKind, path::PathBuf, process};
+
+use anyhow::{anyhow, Result};
+
+use crate::shutdown;
+
+/// A file holding our process id for init scripts, removed again on drop
+/// or when the process is stopped by a signal.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes our process id to `path`. An existing file naming a process
+    /// that is still running means another instance is up, and is refused
+    /// unless `force` is set. One naming a process that is gone is replaced.
+    pub fn create(path: PathBuf, force: bool) -> Result<Self> {
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let pid = contents.trim().parse::<u32>().ok();
+                if let Some(pid) = pid.filter(|pid| !force && is_running(*pid)) {
+                    return Err(anyhow!(
+                        "{} names process {}, which is still running. Pass --force to start anyway.",
+                        path.display(),
+                        pid
+                    ));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("Unable to read {}: {}", path.display(), e)),
+        }
+        fs::write(&path, format!("{}\n", process::id()))
+            .map_err(|e| anyhow!("Unable to write {}: {}", path.display(), e))?;
+        shutdown::remove_on_termination(path.clone())?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Whether a process with the id exists, whoever it belongs to.
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: signal 0 is never delivered, kill only checks that the
+    // process exists and could be signalled.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to tell, any process id is taken to be running.
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
@@ -0,0 +1,69 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+
+/// Prefix marking a template value as a path to read the body from.
+const FILE_PREFIX: char = '@';
+
+/// Placeholder substituted with the label of the token presented.
+const LABEL_PLACEHOLDER: &str = "{label}";
+
+/// Operator supplied body for a response, so that mellon can mimic the error
+/// contract of whatever API it sits in front of.
+#[derive(Debug, Clone)]
+pub struct ResponseTemplate {
+    body: String,
+    content_type: String,
+}
+
+impl ResponseTemplate {
+    /// Builds a template from an inline body, or from a file when the value
+    /// is given as `@<path>`. Without an explicit content type one is
+    /// inferred from the file extension, then from the body itself.
+    pub fn load(value: &str, content_type: Option<&str>) -> Result<Self> {
+        let (body, extension) = match value.strip_prefix(FILE_PREFIX) {
+            Some(path) => {
+                let body = fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Unable to read response template {}: {}", path, e))?;
+                let extension = Path::new(path)
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .map(str::to_ascii_lowercase);
+                (body, extension)
+            }
+            None => (value.to_string(), None),
+        };
+        let content_type = match content_type {
+            Some(content_type) => content_type.to_string(),
+            None => infer_content_type(&body, extension.as_deref()).to_string(),
+        };
+        Ok(ResponseTemplate { body, content_type })
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Fills in the placeholders, an unknown label becomes an empty string.
+    pub fn render(&self, label: Option<&str>) -> String {
+        self.body
+            .replace(LABEL_PLACEHOLDER, label.unwrap_or_default())
+    }
+}
+
+fn infer_content_type(body: &str, extension: Option<&str>) -> &'static str {
+    match extension {
+        Some("json") => return "application/json",
+        Some("html" | "htm") => return "text/html",
+        Some("xml") => return "application/xml",
+        _ => {}
+    }
+    let body = body.trim_start();
+    if body.starts_with('{') || body.starts_with('[') {
+        "application/json"
+    } else if body.starts_with('<') {
+        "text/html"
+    } else {
+        "text/plain"
+    }
+}
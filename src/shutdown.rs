@@ -0,0 +1,45 @@
+use std::{
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::Result;
+
+/// Files to remove should the process be stopped by a signal.
+static REMOVE_ON_TERMINATION: OnceLock<Mutex<Vec<PathBuf>>> = OnceLock::new();
+
+/// The accept loop never returns on its own, so the server is usually
+/// stopped by a signal which would skip `Drop`. Files registered here are
+/// removed then as well. A single handler serves every file, so that none
+/// is left behind by another handler exiting first.
+#[cfg(unix)]
+pub fn remove_on_termination(path: PathBuf) -> Result<()> {
+    use signal_hook::{
+        consts::{SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+
+    let mut first = false;
+    let paths = REMOVE_ON_TERMINATION.get_or_init(|| {
+        first = true;
+        Mutex::new(Vec::new())
+    });
+    paths.lock().unwrap_or_else(|e| e.into_inner()).push(path);
+    if first {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        std::thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                for path in paths.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+                    let _ = std::fs::remove_file(path);
+                }
+                std::process::exit(128 + signal);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn remove_on_termination(_path: PathBuf) -> Result<()> {
+    Ok(())
+}
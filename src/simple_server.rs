@@ -1,118 +1,1423 @@
-use crate::tokens::token_store::TokenStore;
+use crate::access_log::{AccessEntry, LogFormat};
+use crate::base64;
+use crate::connection::Connection;
+use crate::forwarded::{self, Cidr};
+use crate::log;
+use crate::response_template::ResponseTemplate;
+use crate::tokens::store_error::TokenStoreError;
+use crate::tokens::token_store::{Reload, TokenStore, TokenUse};
+use crate::worker_pool::WorkerPool;
 use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
 use std::{
-    io::{prelude::*, BufReader},
+    any::Any,
+    fmt::Display,
+    fs,
+    io::{self, prelude::*, BufReader},
     net::{TcpListener, TcpStream},
-    time::Duration,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::{Duration, Instant},
 };
+use subtle::ConstantTimeEq;
+
+/// Default cap on the length of the request line (method, path and version).
+pub const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+
+/// One worker per CPU, or just the one when that cannot be told.
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, |workers| workers.get())
+}
+
+/// Hosts starting with this are taken to be the path of a Unix socket.
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Default delay in milliseconds ahead of the first bind retry.
+pub const DEFAULT_BIND_RETRY_DELAY_MS: u64 = 250;
+
+/// Default wait in seconds for each read of a request, on top of any
+/// overall deadline, and for each write of the response.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Where token introspection is answered, when enabled.
+const INTROSPECTION_PATH: &str = "/introspect";
+
+/// Body answered on the health path.
+const HEALTHY_BODY: &[u8] = br#"{"status":"ok"}"#;
+
+/// Where the store is reloaded on request, when an admin token is set.
+const RELOAD_PATH: &str = "/admin/reload";
+
+/// Body answered once the store has been reloaded.
+const RELOADED_BODY: &[u8] = br#"{"status":"reloaded"}"#;
+
+/// Largest introspection request body read, a token and a hint or two.
+const MAX_INTROSPECTION_BODY: u64 = 8 * 1024;
 
 enum HttpResponse {
-    Ok,
+    /// Carries the label of the token let in, when there was one.
+    Ok(Option<String>),
+    BadRequest,
     Unauthorised,
+    /// Carries the label of the token turned away.
+    Forbidden(String),
+    /// The path is not the one guarded, whatever token came with it.
+    NotFound,
+    RequestTimeout,
+    PayloadTooLarge,
+    UriTooLong,
     ServerError,
+    /// The store has not been loaded yet, the client should try again shortly.
+    ServiceUnavailable,
+    /// Answer to an introspection request, carries the JSON body.
+    Introspection(String),
+    /// Answer on the health path, whatever token came with it.
+    Healthy,
+    /// The store was reloaded at the request of an admin.
+    Reloaded,
 }
 
 impl HttpResponse {
-    fn as_str(&self) -> &str {
+    fn status_line(&self) -> &str {
+        match self {
+            HttpResponse::Ok(_)
+            | HttpResponse::Introspection(_)
+            | HttpResponse::Healthy
+            | HttpResponse::Reloaded => "HTTP/1.1 200 OK",
+            HttpResponse::BadRequest => "HTTP/1.1 400 BAD REQUEST",
+            HttpResponse::Unauthorised => "HTTP/1.1 401 UNAUTHORISED",
+            HttpResponse::Forbidden(_) => "HTTP/1.1 403 FORBIDDEN",
+            HttpResponse::NotFound => "HTTP/1.1 404 NOT FOUND",
+            HttpResponse::RequestTimeout => "HTTP/1.1 408 REQUEST TIMEOUT",
+            HttpResponse::PayloadTooLarge => "HTTP/1.1 413 PAYLOAD TOO LARGE",
+            HttpResponse::UriTooLong => "HTTP/1.1 414 URI TOO LONG",
+            HttpResponse::ServerError => "HTTP/1.1 500 INTERNAL SERVER ERROR",
+            HttpResponse::ServiceUnavailable => "HTTP/1.1 503 SERVICE UNAVAILABLE",
+        }
+    }
+
+    fn status_code(&self) -> u16 {
+        match self {
+            HttpResponse::Ok(_)
+            | HttpResponse::Introspection(_)
+            | HttpResponse::Healthy
+            | HttpResponse::Reloaded => 200,
+            HttpResponse::BadRequest => 400,
+            HttpResponse::Unauthorised => 401,
+            HttpResponse::Forbidden(_) => 403,
+            HttpResponse::NotFound => 404,
+            HttpResponse::RequestTimeout => 408,
+            HttpResponse::PayloadTooLarge => 413,
+            HttpResponse::UriTooLong => 414,
+            HttpResponse::ServerError => 500,
+            HttpResponse::ServiceUnavailable => 503,
+        }
+    }
+
+    fn label(&self) -> Option<&str> {
         match self {
-            HttpResponse::Ok => "HTTP/1.1 200 OK\r\n\r\n",
-            HttpResponse::Unauthorised => "HTTP/1.1 401 UNAUTHORISED\r\n\r\n",
-            HttpResponse::ServerError => "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\n",
+            HttpResponse::Ok(label) => label.as_deref(),
+            HttpResponse::Forbidden(label) => Some(label),
+            _ => None,
+        }
+    }
+
+    fn template<'a>(&self, options: &'a ServerOptions) -> Option<&'a ResponseTemplate> {
+        match self {
+            HttpResponse::Ok(_) => options.ok_body.as_ref(),
+            HttpResponse::Unauthorised | HttpResponse::Forbidden(_) => {
+                options.unauthorised_body.as_ref()
+            }
+            _ => None,
+        }
+    }
+
+    /// Headers particular to the response, each ending in CRLF.
+    fn headers(&self, options: &ServerOptions) -> String {
+        match self {
+            HttpResponse::ServiceUnavailable => "Retry-After: 1\r\n".to_string(),
+            // a label that would break the header is better left unsaid
+            HttpResponse::Ok(Some(label))
+                if options.label_header && !label.chars().any(char::is_control) =>
+            {
+                format!("{}: {}\r\n", options.label_header_name(), label)
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// The content type and body, responses other than introspection,
+    /// health and reloads only carry a body when the operator has configured
+    /// a template for them.
+    fn body<'a>(&self, options: &'a ServerOptions) -> Option<(&'a str, Vec<u8>)> {
+        match self {
+            HttpResponse::Introspection(json) => {
+                Some(("application/json", json.clone().into_bytes()))
+            }
+            HttpResponse::Healthy => Some(("application/json", HEALTHY_BODY.to_vec())),
+            HttpResponse::Reloaded => Some(("application/json", RELOADED_BODY.to_vec())),
+            _ => self.template(options).map(|template| {
+                (
+                    template.content_type(),
+                    template.render(self.label()).into_bytes(),
+                )
+            }),
         }
     }
 
-    fn as_bytes(&self) -> &[u8] {
-        return self.as_str().as_bytes();
+    fn render(&self, options: &ServerOptions) -> (String, Vec<u8>) {
+        let server = match options.server_header.as_ref() {
+            Some(server) => format!("Server: {}\r\n", server),
+            None => String::new(),
+        };
+        match self.body(options) {
+            Some((content_type, body)) => {
+                let head = format!(
+                    "{}\r\n{}{}{}Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                    self.status_line(),
+                    server,
+                    CONNECTION_CLOSE,
+                    self.headers(options),
+                    content_type,
+                    body.len()
+                );
+                (head, body)
+            }
+            None => (
+                format!(
+                    "{}\r\n{}{}{}\r\n",
+                    self.status_line(),
+                    server,
+                    CONNECTION_CLOSE,
+                    self.headers(options)
+                ),
+                Vec::new(),
+            ),
+        }
+    }
+}
+
+/// The parts of a request we act upon.
+struct Request {
+    /// As received, for the access log.
+    line: String,
+    method: String,
+    uri: String,
+    headers: Headers,
+    /// Only read for introspection, empty for anything else.
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The method and URI the token is being used for. Behind a proxy these
+    /// are those of the original request, passed along in headers.
+    fn target(&self) -> (&str, &str) {
+        (
+            self.headers
+                .original_method
+                .as_deref()
+                .unwrap_or(&self.method),
+            self.headers.original_uri.as_deref().unwrap_or(&self.uri),
+        )
+    }
+}
+
+#[derive(Default)]
+struct Headers {
+    /// Every bearer token presented, a client may send several.
+    auth_tokens: Vec<String>,
+    original_method: Option<String>,
+    original_uri: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// Every X-Forwarded-For header, joined as though sent as one.
+    forwarded_for: Option<String>,
+    /// Length of the body following the headers, which is only read for
+    /// introspection.
+    content_length: u64,
+}
+
+/// Most bearer tokens a request may present, each one costs a lookup.
+const MAX_AUTH_TOKENS: usize = 8;
+
+const BEARER: &str = "Bearer ";
+
+const BASIC: &str = "Basic ";
+
+/// Which part of HTTP Basic credentials is matched against the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BasicSecret {
+    /// The password, whatever the user name.
+    Password,
+    /// The whole of `user:password`.
+    Credentials,
+}
+
+/// The secret held by base64 encoded `user:password` credentials, none when
+/// they do not decode or lack the colon.
+fn basic_secret(encoded: &str, part: BasicSecret) -> Option<String> {
+    let credentials = String::from_utf8(base64::decode(encoded)?).ok()?;
+    match part {
+        BasicSecret::Password => Some(credentials.split_once(':')?.1.to_string()),
+        BasicSecret::Credentials => Some(credentials),
+    }
+}
+
+/// Splits an Authorization value such as `Bearer a, Bearer b` into the
+/// tokens it holds. A comma only separates credentials when another one
+/// follows it, so a token holding a comma is kept whole.
+fn bearer_tokens(value: &str) -> Vec<&str> {
+    let mut credentials = Vec::new();
+    let mut start = 0;
+    for (comma, _) in value.match_indices(',') {
+        if value[comma + 1..].trim_start().starts_with(BEARER) {
+            credentials.push(&value[start..comma]);
+            start = comma + 1;
+        }
+    }
+    credentials.push(&value[start..]);
+    credentials
+        .into_iter()
+        .filter_map(|credential| credential.trim().strip_prefix(BEARER))
+        .collect()
+}
+
+/// How the headers say the body is framed, checked for disagreements.
+#[derive(Default)]
+struct Framing {
+    content_length: Option<u64>,
+    chunked: bool,
+    anomaly: Option<&'static str>,
+}
+
+impl Framing {
+    fn content_length(&mut self, value: &str) {
+        match (value.parse::<u64>(), self.content_length) {
+            (Err(_), _) => self.flag("invalid Content-Length"),
+            (Ok(_), Some(_)) => self.flag("more than one Content-Length"),
+            (Ok(length), None) => self.content_length = Some(length),
+        }
+    }
+
+    fn transfer_encoding(&mut self) {
+        if self.chunked {
+            self.flag("more than one Transfer-Encoding");
+        }
+        self.chunked = true;
+    }
+
+    /// Keeps the first anomaly, that is the one worth reporting.
+    fn flag(&mut self, anomaly: &'static str) {
+        self.anomaly.get_or_insert(anomaly);
+    }
+
+    fn check(mut self) -> Result<u64, RequestError> {
+        if self.chunked && self.content_length.is_some() {
+            self.flag("both Content-Length and Transfer-Encoding");
+        }
+        match self.anomaly {
+            Some(anomaly) => Err(RequestError::AmbiguousFraming(anomaly)),
+            None => Ok(self.content_length.unwrap_or_default()),
+        }
+    }
+}
+
+/// Problems with the request itself, as opposed to failures on our side.
+#[derive(Debug)]
+enum RequestError {
+    Timeout,
+    /// The client sent nothing for longer than the read timeout, carries
+    /// what was being read.
+    Idle(&'static str),
+    UriTooLong,
+    /// More bearer tokens were presented than we are willing to try.
+    TooManyTokens,
+    /// Basic credentials that are not base64 encoded `user:password`.
+    MalformedBasic,
+    /// An introspection request with more body than a token needs.
+    BodyTooLarge,
+    /// Where the body ends is open to interpretation, which a proxy in
+    /// front of us may settle differently. Carries what was ambiguous.
+    AmbiguousFraming(&'static str),
+}
+
+impl RequestError {
+    fn response(&self) -> HttpResponse {
+        match self {
+            RequestError::Timeout | RequestError::Idle(_) => HttpResponse::RequestTimeout,
+            RequestError::UriTooLong => HttpResponse::UriTooLong,
+            RequestError::BodyTooLarge => HttpResponse::PayloadTooLarge,
+            // auth_request turns anything but 401 and 403 into a 500, and
+            // credentials we cannot read are simply not ones we know
+            RequestError::MalformedBasic => HttpResponse::Unauthorised,
+            RequestError::TooManyTokens | RequestError::AmbiguousFraming(_) => {
+                HttpResponse::BadRequest
+            }
+        }
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "Request exceeded its time budget"),
+            RequestError::Idle(what) => write!(f, "Connection timed out while reading {}", what),
+            RequestError::UriTooLong => write!(f, "Request line exceeds maximum length"),
+            RequestError::TooManyTokens => {
+                write!(f, "Request presents more than {} tokens", MAX_AUTH_TOKENS)
+            }
+            RequestError::MalformedBasic => write!(f, "Malformed Basic credentials"),
+            RequestError::BodyTooLarge => write!(
+                f,
+                "Introspection request body exceeds {} bytes",
+                MAX_INTROSPECTION_BODY
+            ),
+            RequestError::AmbiguousFraming(what) => {
+                write!(f, "Rejected possible request smuggling: {}", what)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Tunables for the server, kept apart from the store it guards.
+pub struct ServerOptions {
+    pub max_request_line: usize,
+    /// Requests taking longer than this from accept to response are logged.
+    pub slow_threshold: Option<Duration>,
+    /// Disables Nagle's algorithm on accepted connections.
+    pub tcp_nodelay: bool,
+    /// Enables TCP keepalive probes after the connection has idled this long.
+    pub tcp_keepalive: Option<Duration>,
+    /// Logs the decision for every request but always lets it through.
+    pub dry_run: bool,
+    /// Number of times to retry binding the host before giving up.
+    pub bind_retries: u32,
+    /// Delay ahead of the first bind retry, doubled for each one after.
+    pub bind_retry_delay: Duration,
+    /// Total time allowed to read a request, however steadily it trickles in.
+    pub max_request_time: Option<Duration>,
+    /// Longest wait for the client to send anything more of its request.
+    /// Must not be zero.
+    pub read_timeout: Duration,
+    /// Longest wait for the client to take anything more of the response.
+    /// Must not be zero.
+    pub write_timeout: Duration,
+    /// Body sent along with a 200.
+    pub ok_body: Option<ResponseTemplate>,
+    /// Body sent along with a 401 or 403.
+    pub unauthorised_body: Option<ResponseTemplate>,
+    /// Stops after serving a single connection.
+    pub once: bool,
+    /// File to write the port listened on to, handy when binding port 0.
+    pub port_file: Option<PathBuf>,
+    /// Lets requests through when the store cannot be consulted, rather
+    /// than turning them away.
+    pub fail_open: bool,
+    /// How often to check the store file for changes, never when absent.
+    pub reload_interval: Option<Duration>,
+    /// Value of the Server header, none is sent when absent.
+    pub server_header: Option<String>,
+    /// Whether and how a line is logged for every request.
+    pub log_format: LogFormat,
+    /// Proxies whose X-Forwarded-For is believed when logging the client.
+    pub trusted_proxies: Vec<Cidr>,
+    /// The only path answered, every other one is not found. All paths
+    /// are guarded when absent.
+    pub guard_path: Option<String>,
+    /// Answers RFC 7662 token introspection on `POST /introspect`.
+    pub introspection: bool,
+    /// Also takes the secret from Basic credentials, ignored when absent.
+    pub accept_basic: Option<BasicSecret>,
+    /// Answered with a 200 without looking for a token, for load balancer
+    /// health checks. No path is when absent.
+    pub health_path: Option<String>,
+    /// Names the token a request was let in by in an `X-Auth-Label` header,
+    /// or `X-Forwarded-User` with `forward_auth`.
+    pub label_header: bool,
+    /// Bearer token an admin presents to `POST /admin/reload` to have the
+    /// store reloaded. Tokens in the store are no use there, and no reload
+    /// is answered when absent.
+    pub admin_token: Option<String>,
+    /// Takes the original request from the `X-Forwarded-Method` and
+    /// `X-Forwarded-Uri` headers Traefik's ForwardAuth sends, rather than
+    /// the `X-Original-Method` and `X-Original-URI` set up for nginx.
+    pub forward_auth: bool,
+    /// Threads connections are served on, so that a slow client only holds
+    /// up its own. `once` serves its connection on the accepting thread.
+    pub workers: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            slow_threshold: None,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            dry_run: false,
+            bind_retries: 0,
+            bind_retry_delay: Duration::from_millis(DEFAULT_BIND_RETRY_DELAY_MS),
+            max_request_time: None,
+            read_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            write_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            ok_body: None,
+            unauthorised_body: None,
+            once: false,
+            port_file: None,
+            fail_open: false,
+            reload_interval: None,
+            server_header: None,
+            log_format: LogFormat::default(),
+            trusted_proxies: Vec::new(),
+            guard_path: None,
+            introspection: false,
+            accept_basic: None,
+            health_path: None,
+            label_header: false,
+            admin_token: None,
+            forward_auth: false,
+            workers: default_workers(),
+        }
+    }
+}
+
+impl ServerOptions {
+    /// The headers naming the method and URI of the original request. Only
+    /// one pair is read, so that a client cannot slip the other past a
+    /// proxy that only sets one.
+    fn original_headers(&self) -> (&'static str, &'static str) {
+        match self.forward_auth {
+            true => ("X-Forwarded-Method", "X-Forwarded-Uri"),
+            false => ("X-Original-Method", "X-Original-URI"),
+        }
+    }
+
+    fn label_header_name(&self) -> &'static str {
+        match self.forward_auth {
+            true => FORWARDED_USER_HEADER,
+            false => LABEL_HEADER,
+        }
+    }
+
+    /// Applies the socket options to a TCP connection as the server does
+    /// to each one it accepts.
+    pub fn configure_socket(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.configure(self)
     }
 }
 
 pub struct MellonServer {
-    token_store: TokenStore,
+    /// Read by the workers while checking a token, written to by reloads
+    /// on the accepting thread.
+    token_store: RwLock<TokenStore>,
+    /// Held through a reload, so that a second one never waits on the write
+    /// lock, and holds up the workers, while the first is still fetching.
+    reloading: Mutex<()>,
     host_name: String,
+    options: ServerOptions,
+    /// Raised by SIGHUP, the store is reloaded ahead of the next request.
+    reload_requested: Arc<AtomicBool>,
+    /// Raised when the store file is seen to have changed, see `watch_store`.
+    store_changed: Arc<AtomicBool>,
+    /// Raised while a fetched store is being reloaded.
+    fetching: AtomicBool,
+    /// Raised by SIGUSR2, stats are logged ahead of the next request.
+    stats_requested: Arc<AtomicBool>,
+    outcomes: Mutex<Outcomes>,
+}
+
+/// How the requests served since start up were answered.
+#[derive(Default)]
+struct Outcomes {
+    allowed: u64,
+    unauthorised: u64,
+    forbidden: u64,
+    /// Malformed requests and failures on our side, by far the rarest.
+    other: u64,
+}
+
+/// How a connection was answered.
+struct Answered {
+    /// The status sent.
+    status: u16,
+    /// The status the request earned, which a dry run sends as 200.
+    verdict: u16,
+}
+
+impl Outcomes {
+    fn record(&mut self, status: Option<u16>) {
+        match status {
+            Some(200) => self.allowed += 1,
+            Some(401) => self.unauthorised += 1,
+            Some(403) => self.forbidden += 1,
+            _ => self.other += 1,
+        }
+    }
 }
 
 impl MellonServer {
-    pub fn serve(host_name: String, token_store: TokenStore) -> Result<()> {
-        let server = MellonServer {
-            token_store,
+    /// Serves until the listener closes. In `once` mode this returns after
+    /// the first connection, with the status it was answered with.
+    pub fn serve(
+        host_name: String,
+        token_store: TokenStore,
+        options: ServerOptions,
+    ) -> Result<Option<u16>> {
+        let server = Arc::new(MellonServer {
+            token_store: RwLock::new(token_store),
+            reloading: Mutex::new(()),
             host_name,
-        };
+            options,
+            reload_requested: Arc::new(AtomicBool::new(false)),
+            store_changed: Arc::new(AtomicBool::new(false)),
+            fetching: AtomicBool::new(false),
+            stats_requested: Arc::new(AtomicBool::new(false)),
+            outcomes: Mutex::new(Outcomes::default()),
+        });
+        #[cfg(unix)]
+        signal_hook::flag::register(
+            signal_hook::consts::SIGHUP,
+            Arc::clone(&server.reload_requested),
+        )?;
+        #[cfg(unix)]
+        signal_hook::flag::register(
+            signal_hook::consts::SIGUSR2,
+            Arc::clone(&server.stats_requested),
+        )?;
+        if let Some(interval) = server.options.reload_interval {
+            let token_store = server.store();
+            let path = (!token_store.is_remote()).then(|| token_store.file_path().to_path_buf());
+            drop(token_store);
+            let store_changed = Arc::clone(&server.store_changed);
+            thread::spawn(move || watch_store(path, interval, store_changed));
+        }
+        if server.options.fail_open {
+            log!("WARNING: failing open, requests are let through whenever the token store cannot be consulted");
+        }
         server.listen()
     }
 
-    fn listen(&self) -> Result<()> {
-        let listener = match TcpListener::bind(&self.host_name) {
-            Ok(listener) => listener,
+    fn store(&self) -> RwLockReadGuard<'_, TokenStore> {
+        self.token_store.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn store_mut(&self) -> RwLockWriteGuard<'_, TokenStore> {
+        self.token_store.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits for the workers to be done with the store, which they only
+    /// hold while checking a token, never while reading a request. A
+    /// fetched store is reloaded on a thread of its own, a slow fetch must
+    /// not hold up handing out the connections.
+    fn reload_if_requested(self: &Arc<Self>) {
+        let signalled = self.reload_requested.swap(false, Ordering::SeqCst);
+        let changed = self.store_changed.swap(false, Ordering::SeqCst);
+        if !signalled && !changed {
+            return;
+        }
+        // fetching happens every interval, and is only worth a mention
+        // when it comes back different
+        let fetched = !signalled && self.store().is_remote();
+        if !signalled && !fetched {
+            log!("Token store file changed, reloading");
+        }
+        if !self.store().is_remote() {
+            self.reload_logged(fetched);
+            return;
+        }
+        if self.fetching.swap(true, Ordering::SeqCst) {
+            // a signal is seen to once the fetch under way is done
+            if signalled {
+                self.reload_requested.store(true, Ordering::SeqCst);
+            }
+            return;
+        }
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            server.reload_logged(fetched);
+            server.fetching.store(false, Ordering::SeqCst);
+        });
+    }
+
+    fn reload_logged(&self, quiet: bool) {
+        // the tokens already held keep being served
+        if let Err(e) = self.reload_store(quiet) {
+            log!("Failed to reload token store: {}", e);
+        }
+    }
+
+    /// Rereads the store and logs what changed, saying nothing of a reload
+    /// that changed nothing when `quiet`.
+    fn reload_store(&self, quiet: bool) -> Result<()> {
+        let _reloading = self.reloading.lock().unwrap_or_else(|e| e.into_inner());
+        // the workers keep serving while a fetch is under way. A file is
+        // read under the write lock, so that no flush of uses comes between
+        // reading it and taking it in
+        let fetched = match self.store().is_remote() {
+            true => Some(self.store().read_contents()?),
+            false => None,
+        };
+        let mut token_store = self.store_mut();
+        let refreshed = match fetched {
+            Some(contents) => token_store.refresh_from(&contents)?,
+            None => token_store.refresh()?,
+        };
+        drop(token_store);
+        match refreshed {
+            Reload::Full => log!("Reloaded token store in full"),
+            Reload::Incremental {
+                added,
+                updated,
+                removed,
+            } if quiet && added.is_empty() && updated.is_empty() && removed.is_empty() => {}
+            Reload::Incremental {
+                added,
+                updated,
+                removed,
+            } => log!(
+                "Reloaded token store: added [{}], updated [{}], removed [{}]",
+                added.join(", "),
+                updated.join(", "),
+                removed.join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    /// Logs a snapshot of the store and of how requests have been answered,
+    /// for when there is nothing else to ask.
+    fn log_stats_if_requested(&self) {
+        if !self.stats_requested.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let token_store = self.store();
+        let now = token_store.now().unwrap_or_default();
+        let (tokens, expired) = match token_store.iter() {
+            Ok(tokens) => tokens.fold((0, 0), |(tokens, expired), token| {
+                (tokens + 1, expired + usize::from(token.is_expired(now)))
+            }),
             Err(e) => {
-                eprintln!("Failed to bind to {}: {}", self.host_name, e);
-                return Err(e.into());
+                log!("Failed to gather stats: {}", e);
+                return;
             }
         };
+        let outcomes = self.outcomes.lock().unwrap_or_else(|e| e.into_inner());
+        log!(
+            "Stats: {} tokens of which {} expired, {} tombstones. Since start up {} let in, {} unauthorised, {} forbidden, {} otherwise answered",
+            tokens,
+            expired,
+            token_store.tombstones().count(),
+            outcomes.allowed,
+            outcomes.unauthorised,
+            outcomes.forbidden,
+            outcomes.other
+        );
+    }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => self
-                    .serve_connection(stream)
-                    .unwrap_or_else(|e| eprintln!("Failed to serve request {}", e)),
-                Err(e) => eprintln!("Error accepting connection: {}", e),
+    fn listen(self: &Arc<Self>) -> Result<Option<u16>> {
+        #[cfg(unix)]
+        if let Some(path) = self.host_name.strip_prefix(UNIX_SOCKET_PREFIX) {
+            let listener = crate::connection::UnixSocketListener::bind(path.into())?;
+            return self.accept_loop(listener.incoming());
+        }
+        #[cfg(not(unix))]
+        if self.host_name.starts_with(UNIX_SOCKET_PREFIX) {
+            return Err(anyhow::anyhow!(
+                "Unix sockets are not supported on this platform"
+            ));
+        }
+
+        let listener = match inherited_listener() {
+            Some(listener) => {
+                println!("Using listener passed in by systemd");
+                listener
             }
+            None => self.bind()?,
+        };
+        self.report_port(&listener)?;
+        self.accept_loop(listener.incoming())
+    }
+
+    /// Says which port was bound, which is only known once bound when the
+    /// host asks for port 0.
+    fn report_port(&self, listener: &TcpListener) -> Result<()> {
+        let local_addr = listener.local_addr()?;
+        if self.host_name.ends_with(":0") {
+            log!("Listening on {}", local_addr);
+        }
+        if let Some(port_file) = self.options.port_file.as_ref() {
+            // written aside and renamed so that nobody reads a half written port
+            let partial = port_file.with_extension("partial");
+            fs::write(&partial, format!("{}\n", local_addr.port()))?;
+            fs::rename(&partial, port_file)?;
         }
         Ok(())
     }
 
-    fn serve_connection(&self, stream: TcpStream) -> Result<()> {
-        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
-        let auth_token = self.extract_auth_token(&stream);
-        if let Err(e) = auth_token {
-            self.respond(stream, HttpResponse::ServerError)?;
-            return Err(e);
-        }
-        // if no auth header, cannot be valid
-        match auth_token? {
-            // i.e. we have found the auth token from the headers
-            // now we just test it against the token store
-            Some(auth_token) => match self.token_store.contains_token(&auth_token) {
-                Ok(result) => match result {
-                    true => self.respond(stream, HttpResponse::Ok)?,
-                    false => self.respond(stream, HttpResponse::Unauthorised)?,
-                },
+    /// Hands each connection to a worker. The workers are left to finish
+    /// the connections they were given before this returns.
+    fn accept_loop<S: Connection + Send + 'static>(
+        self: &Arc<Self>,
+        incoming: impl Iterator<Item = io::Result<S>>,
+    ) -> Result<Option<u16>> {
+        let pool = match self.options.once {
+            true => None,
+            false => Some(WorkerPool::new(self.options.workers)?),
+        };
+        for stream in incoming {
+            match stream {
+                Ok(stream) => {
+                    self.reload_if_requested();
+                    self.log_stats_if_requested();
+                    let accepted_at = Instant::now();
+                    let Some(pool) = pool.as_ref() else {
+                        return Ok(self.serve_accepted(stream, accepted_at));
+                    };
+                    let server = Arc::clone(self);
+                    pool.execute(move || {
+                        server.serve_accepted(stream, accepted_at);
+                    });
+                }
+                Err(e) => log!("Error accepting connection: {}", e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Serves a connection and accounts for it, returning the status it
+    /// was answered with.
+    fn serve_accepted<S: Connection>(&self, stream: S, accepted_at: Instant) -> Option<u16> {
+        let peer = stream.peer();
+        // a bug tripped by one connection must not take the others down with it
+        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.serve_connection(stream, accepted_at)
+        }));
+        let answered = match served {
+            Ok(served) => served
+                .inspect_err(|e| log!("Failed to serve request {}", e))
+                .ok(),
+            Err(panic) => {
+                log!(
+                    "Panicked while serving request, dropped the connection: {}",
+                    panic_message(&*panic)
+                );
+                None
+            }
+        };
+        // a dry run counts what it would have answered
+        self.outcomes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(answered.as_ref().map(|answered| answered.verdict));
+        let elapsed = accepted_at.elapsed();
+        if self
+            .options
+            .slow_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            match peer {
+                Some(peer) => log!("Slow request from {}: took {}ms", peer, elapsed.as_millis()),
+                None => log!("Slow request: took {}ms", elapsed.as_millis()),
+            }
+        }
+        answered.map(|answered| answered.status)
+    }
+
+    /// Binds the host, retrying with exponential backoff while the port is
+    /// still held by e.g. a previous instance that is shutting down.
+    fn bind(&self) -> Result<TcpListener> {
+        let mut delay = self.options.bind_retry_delay;
+        let mut first_error = None;
+        for attempt in 0..=self.options.bind_retries {
+            if attempt > 0 {
+                log!(
+                    "Retrying bind to {} in {}ms (attempt {} of {})",
+                    self.host_name,
+                    delay.as_millis(),
+                    attempt,
+                    self.options.bind_retries
+                );
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            match TcpListener::bind(&self.host_name) {
+                Ok(listener) => return Ok(listener),
                 Err(e) => {
-                    self.respond(stream, HttpResponse::ServerError)?;
-                    return Err(e);
+                    log!("Failed to bind to {}: {}", self.host_name, e);
+                    first_error.get_or_insert(e);
                 }
-            },
-            // No auth token obviously means request cannot be authorized
-            None => self.respond(stream, HttpResponse::Unauthorised)?,
+            }
+        }
+        match first_error {
+            Some(e) => Err(e.into()),
+            None => Err(anyhow::anyhow!("Failed to bind to {}", self.host_name)),
+        }
+    }
+
+    /// Answers a single connection, returning how it was answered. Errors
+    /// are only returned when no response could be sent.
+    fn serve_connection<S: Connection>(
+        &self,
+        mut stream: S,
+        accepted_at: Instant,
+    ) -> Result<Answered> {
+        stream.configure(&self.options)?;
+        let peer = stream.peer_ip();
+        let deadline = self
+            .options
+            .max_request_time
+            .map(|max_request_time| accepted_at + max_request_time);
+        let request = self.read_request(&mut stream, deadline);
+        // HEAD gets the same response as GET, less the body
+        let head_only = matches!(&request, Ok(request) if request.method == "HEAD");
+        let body_pending = match &request {
+            Ok(request) => request.headers.content_length > request.body.len() as u64,
+            // the length of the body is anyone's guess
+            Err(e) => matches!(
+                e.downcast_ref::<RequestError>(),
+                Some(RequestError::AmbiguousFraming(_) | RequestError::BodyTooLarge)
+            ),
+        };
+        let introspecting =
+            matches!(&request, Ok(request) if self.introspects(&request.method, &request.uri));
+        let reloading = matches!(&request, Ok(request) if self.reloads(request));
+        let (request, outcome) = match request {
+            Ok(request) if reloading => {
+                let outcome = self.admin_reload(&request);
+                (Some(request), outcome)
+            }
+            Ok(request) if introspecting => {
+                let outcome = self.introspect(&request);
+                (Some(request), outcome)
+            }
+            Ok(request) if self.checks_health(&request) => {
+                (Some(request), Ok(HttpResponse::Healthy))
+            }
+            Ok(request) if !self.guards(&request) => (Some(request), Ok(HttpResponse::NotFound)),
+            Ok(request) => {
+                let outcome = self.authorise(&request);
+                (Some(request), outcome)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        let (response, failure) = match outcome {
+            Ok(response) => (response, None),
+            Err(e) => {
+                // malformed requests get told what was wrong, a store still
+                // loading asks for a retry, anything else is on us
+                let response = match (
+                    e.downcast_ref::<RequestError>(),
+                    e.downcast_ref::<TokenStoreError>(),
+                ) {
+                    (Some(request_error), _) => request_error.response(),
+                    (_, Some(TokenStoreError::NotLoaded)) => HttpResponse::ServiceUnavailable,
+                    _ => HttpResponse::ServerError,
+                };
+                (response, Some(e))
+            }
+        };
+        let verdict = response.status_code();
+        let response = match response {
+            // not being guarded is no verdict on the token
+            HttpResponse::Ok(_) | HttpResponse::NotFound | HttpResponse::Healthy => response,
+            // in a dry run we only say what we would have done, and let it
+            // through, but introspection and reloads are no request to let
+            // through
+            response if self.options.dry_run && !introspecting && !reloading => {
+                log!("Dry run, would have responded {}", response.status_line());
+                HttpResponse::Ok(response.label().map(str::to_string))
+            }
+            response => response,
+        };
+        let status = response.status_code();
+        let body_len = self.respond(&mut stream, &response, head_only)?;
+        let entry = AccessEntry {
+            client: peer.map(|peer| {
+                let forwarded_for = request
+                    .as_ref()
+                    .and_then(|request| request.headers.forwarded_for.as_deref());
+                forwarded::client_ip(peer, forwarded_for, &self.options.trusted_proxies)
+            }),
+            label: response.label(),
+            request_line: request.as_ref().map(|request| request.line.as_str()),
+            status,
+            body_len,
+            referer: request.as_ref().and_then(|r| r.headers.referer.as_deref()),
+            user_agent: request
+                .as_ref()
+                .and_then(|r| r.headers.user_agent.as_deref()),
+        };
+        if let Some(line) = entry.line(self.options.log_format) {
+            log!("{}", line);
+        }
+        if body_pending {
+            linger(&mut stream);
+        }
+        if let Some(e) = failure {
+            log!("Failed to serve request {}", e);
+        }
+        Ok(Answered { status, verdict })
+    }
+
+    /// Whether the request is for a path we guard, the query is not part of it.
+    fn guards(&self, request: &Request) -> bool {
+        let Some(guard_path) = self.options.guard_path.as_deref() else {
+            return true;
+        };
+        uri_path(&request.uri) == guard_path
+    }
+
+    fn checks_health(&self, request: &Request) -> bool {
+        self.options.health_path.as_deref() == Some(uri_path(&request.uri))
+    }
+
+    fn reloads(&self, request: &Request) -> bool {
+        self.options.admin_token.is_some()
+            && request.method == "POST"
+            && uri_path(&request.uri) == RELOAD_PATH
+    }
+
+    /// Reloads the store for a request presenting the admin token, which
+    /// is never looked up in the store. The token is compared in constant
+    /// time, so that how long a refusal takes says nothing of it.
+    fn admin_reload(&self, request: &Request) -> Result<HttpResponse> {
+        let Some(admin_token) = self.options.admin_token.as_deref() else {
+            return Ok(HttpResponse::Unauthorised);
+        };
+        if !request
+            .headers
+            .auth_tokens
+            .iter()
+            .any(|auth_token| bool::from(auth_token.as_bytes().ct_eq(admin_token.as_bytes())))
+        {
+            return Ok(HttpResponse::Unauthorised);
+        }
+        log!("Reload requested by an admin");
+        self.reload_store(false)?;
+        Ok(HttpResponse::Reloaded)
+    }
+
+    fn introspects(&self, method: &str, uri: &str) -> bool {
+        self.options.introspection && method == "POST" && uri_path(uri) == INTROSPECTION_PATH
+    }
+
+    /// Answers an RFC 7662 introspection request for the `token` in the
+    /// form body. The caller presents a token of its own, checked as on any
+    /// other request, and nothing is counted against the one introspected.
+    fn introspect(&self, request: &Request) -> Result<HttpResponse> {
+        match self.consult_store(request)? {
+            HttpResponse::Ok(_) => {}
+            refusal => return Ok(refusal),
+        }
+        let Some(token_string) = form_value(&request.body, "token") else {
+            return Ok(HttpResponse::BadRequest);
+        };
+        let introspection = match self.store().active_token(&token_string)? {
+            Some(token) => {
+                let mut introspection = serde_json::json!({
+                    "active": true,
+                    "sub": token.label,
+                });
+                if let Some(expires_at) = token.expires_at {
+                    introspection["exp"] = expires_at.into();
+                }
+                introspection
+            }
+            None => serde_json::json!({ "active": false }),
+        };
+        Ok(HttpResponse::Introspection(introspection.to_string()))
+    }
+
+    fn authorise(&self, request: &Request) -> Result<HttpResponse> {
+        match self.consult_store(request) {
+            Err(e) if self.options.fail_open => {
+                log!(
+                    "FAILING OPEN: token store could not be consulted, letting the request through: {}",
+                    e
+                );
+                Ok(HttpResponse::Ok(None))
+            }
+            response => response,
         }
-        Ok(())
     }
 
-    fn extract_auth_token(&self, stream: &TcpStream) -> Result<Option<String>> {
-        let buf_reader = BufReader::new(stream);
-        for line in buf_reader.lines() {
-            match line {
-                Ok(line) => {
-                    if let Some(token) = line.strip_prefix("Authorization: Bearer ") {
-                        return Ok(Some(token.to_string()));
+    /// The request is let in when any of the tokens it presents is, tried
+    /// in the order they were sent.
+    fn consult_store(&self, request: &Request) -> Result<HttpResponse> {
+        let auth_tokens = &request.headers.auth_tokens;
+        // No auth token obviously means request cannot be authorized, and a
+        // genuine token turned away says more than one we have never seen
+        let mut refusal = HttpResponse::Unauthorised;
+        for (index, auth_token) in auth_tokens.iter().enumerate() {
+            match self.check_token(request, auth_token)? {
+                HttpResponse::Unauthorised => {}
+                HttpResponse::Ok(label) => {
+                    if auth_tokens.len() > 1 {
+                        log!(
+                            "Let in by token {} of {} presented, labelled {}",
+                            index + 1,
+                            auth_tokens.len(),
+                            label.as_deref().unwrap_or("-")
+                        );
                     }
+                    return Ok(HttpResponse::Ok(label));
+                }
+                response => refusal = response,
+            }
+        }
+        Ok(refusal)
+    }
+
+    fn check_token(&self, request: &Request, auth_token: &str) -> Result<HttpResponse> {
+        let token_store = self.store();
+        let Some(token) = token_store.authenticate(auth_token)? else {
+            return Ok(HttpResponse::Unauthorised);
+        };
+        // scopes go first, a request turned away must not use up the token
+        let (method, uri) = request.target();
+        if !token.allows(method, uri) {
+            return Ok(HttpResponse::Forbidden(token.label.clone()));
+        }
+        match token_store.admit(token)? {
+            TokenUse::Allowed(label) => Ok(HttpResponse::Ok(Some(label))),
+            // the token is genuine, it has simply been used up
+            TokenUse::Exhausted(label) => Ok(HttpResponse::Forbidden(label)),
+            TokenUse::Expired(_) | TokenUse::Unknown => Ok(HttpResponse::Unauthorised),
+        }
+    }
+
+    fn read_request<S: Connection>(
+        &self,
+        stream: &mut S,
+        deadline: Option<Instant>,
+    ) -> Result<Request> {
+        let mut buf_reader = BufReader::new(stream);
+        // the request line is read on its own so that an oversized path is
+        // rejected before we buffer all of it
+        let request_line = self.read_request_line(&mut buf_reader, deadline)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let uri = parts.next().unwrap_or_default().to_string();
+        let headers = self.read_headers(&mut buf_reader, deadline)?;
+        let body = match self.introspects(&method, &uri) {
+            true => read_body(
+                &mut buf_reader,
+                headers.content_length,
+                deadline,
+                self.options.read_timeout,
+            )?,
+            false => Vec::new(),
+        };
+        Ok(Request {
+            line: request_line,
+            method,
+            uri,
+            headers,
+            body,
+        })
+    }
+
+    fn read_headers<S: Connection>(
+        &self,
+        buf_reader: &mut BufReader<&mut S>,
+        deadline: Option<Instant>,
+    ) -> Result<Headers> {
+        let mut headers = Headers::default();
+        let mut framing = Framing::default();
+        let (original_method_header, original_uri_header) = self.options.original_headers();
+        let mut line = String::new();
+        loop {
+            arm_read_timeout(&**buf_reader.get_ref(), deadline, self.options.read_timeout)?;
+            line.clear();
+            match buf_reader.read_line(&mut line) {
+                // a client that has stopped sending is done with its headers,
+                // blank line or not, so there is nothing to wait for
+                Ok(0) => break,
+                Ok(_) => {
+                    let line = line.trim_end_matches(['\r', '\n']);
                     if line.is_empty() {
                         break;
                     }
+                    let Some((name, value)) = line.split_once(':') else {
+                        continue;
+                    };
+                    // "Content-Length : 5" may be honoured upstream yet missed here
+                    if name.ends_with([' ', '\t']) {
+                        framing.flag("whitespace ahead of a header colon");
+                    }
+                    let value = value.trim();
+                    if name.eq_ignore_ascii_case("Authorization") {
+                        let tokens = match (self.options.accept_basic, value.strip_prefix(BASIC)) {
+                            (Some(part), Some(encoded)) => vec![basic_secret(encoded.trim(), part)
+                                .ok_or(RequestError::MalformedBasic)?],
+                            _ => bearer_tokens(value).into_iter().map(String::from).collect(),
+                        };
+                        for token in tokens {
+                            if headers.auth_tokens.len() == MAX_AUTH_TOKENS {
+                                return Err(RequestError::TooManyTokens.into());
+                            }
+                            headers.auth_tokens.push(token);
+                        }
+                    } else if name.eq_ignore_ascii_case(original_method_header) {
+                        headers.original_method = Some(value.to_string());
+                    } else if name.eq_ignore_ascii_case(original_uri_header) {
+                        headers.original_uri = Some(value.to_string());
+                    } else if name.eq_ignore_ascii_case("X-Forwarded-For") {
+                        let forwarded_for = match headers.forwarded_for.take() {
+                            Some(previous) => format!("{}, {}", previous, value),
+                            None => value.to_string(),
+                        };
+                        headers.forwarded_for = Some(forwarded_for);
+                    } else if name.eq_ignore_ascii_case("Referer") {
+                        headers.referer = Some(value.to_string());
+                    } else if name.eq_ignore_ascii_case("User-Agent") {
+                        headers.user_agent = Some(value.to_string());
+                    } else if name.eq_ignore_ascii_case("Content-Length") {
+                        framing.content_length(value);
+                    } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                        framing.transfer_encoding();
+                    }
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                    return Err(anyhow::anyhow!(
-                        "Connection timed out while reading headers"
-                    ));
+                Err(e) if is_timeout(&e) => {
+                    return Err(timed_out(deadline, "headers"));
                 }
                 Err(e) => return Err(e.into()),
             }
         }
-        Ok(None)
+        headers.content_length = framing.check()?;
+        Ok(headers)
     }
 
-    fn respond(&self, mut stream: TcpStream, response: HttpResponse) -> Result<()> {
-        stream.set_write_timeout(Some(Duration::from_secs(30)))?;
-        stream.write_all(response.as_bytes())?;
-        Ok(())
+    fn read_request_line<S: Connection>(
+        &self,
+        reader: &mut BufReader<&mut S>,
+        deadline: Option<Instant>,
+    ) -> Result<String> {
+        let limit = self.options.max_request_line;
+        let mut line = Vec::new();
+        arm_read_timeout(&**reader.get_ref(), deadline, self.options.read_timeout)?;
+        // allow one byte past the limit so that we can tell a line that fits
+        // exactly apart from one that does not
+        reader
+            .by_ref()
+            .take(limit as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .map_err(|e| match is_timeout(&e) {
+                true => timed_out(deadline, "request line"),
+                false => e.into(),
+            })?;
+        if !line.ends_with(b"\n") && line.len() > limit {
+            return Err(RequestError::UriTooLong.into());
+        }
+        Ok(String::from_utf8_lossy(&line).trim_end().to_string())
     }
+
+    /// Writes the response out, returning the length of the body sent.
+    fn respond<S: Connection>(
+        &self,
+        stream: &mut S,
+        response: &HttpResponse,
+        head_only: bool,
+    ) -> Result<usize> {
+        stream.set_write_timeout(Some(self.options.write_timeout))?;
+        let (head, body) = response.render(&self.options);
+        stream.write_all(head.as_bytes())?;
+        if head_only {
+            return Ok(0);
+        }
+        stream.write_all(&body)?;
+        Ok(body.len())
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "no message",
+    }
+}
+
+/// The path of a request URI, less any query or fragment.
+fn uri_path(uri: &str) -> &str {
+    uri.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Reads a body of the given length, or as much of it as the client sends
+/// before closing its end.
+fn read_body<S: Connection>(
+    reader: &mut BufReader<&mut S>,
+    length: u64,
+    deadline: Option<Instant>,
+    read_timeout: Duration,
+) -> Result<Vec<u8>> {
+    if length > MAX_INTROSPECTION_BODY {
+        return Err(RequestError::BodyTooLarge.into());
+    }
+    let mut body = vec![0; length as usize];
+    let mut read = 0;
+    while read < body.len() {
+        arm_read_timeout(&**reader.get_ref(), deadline, read_timeout)?;
+        match reader.read(&mut body[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if is_timeout(&e) => {
+                return Err(timed_out(deadline, "body"));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    body.truncate(read);
+    Ok(body)
+}
+
+/// The first value of a field in an `application/x-www-form-urlencoded`
+/// body, none when it is missing or does not decode.
+fn form_value(body: &[u8], name: &str) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    body.split('&')
+        .filter_map(|field| field.split_once('='))
+        .find(|(key, _)| form_decode(key).as_deref() == Some(name))
+        .and_then(|(_, value)| form_decode(value))
+}
+
+fn form_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Every connection is closed once answered, and clients are told so.
+const CONNECTION_CLOSE: &str = "Connection: close\r\n";
+
+/// Names the token a request was let in by, with `label_header`.
+const LABEL_HEADER: &str = "X-Auth-Label";
+
+/// Takes the place of `LABEL_HEADER` with `forward_auth`, Traefik passes it
+/// upstream when listed in `authResponseHeaders`.
+const FORWARDED_USER_HEADER: &str = "X-Forwarded-User";
+
+/// How much of an unwanted request body is read and thrown away after the
+/// response, and for how long at most.
+const LINGER_BYTES: u64 = 64 * 1024;
+const LINGER_TIME: Duration = Duration::from_millis(200);
+
+/// The body is never needed, so the response goes out without reading it.
+/// Closing with unread data would reset the connection though, which can
+/// lose the response on the way, so some of it is drained first.
+fn linger<S: Connection>(stream: &mut S) {
+    if stream.shutdown_write().is_err() || stream.set_read_timeout(Some(LINGER_TIME)).is_err() {
+        return;
+    }
+    let _ = io::copy(&mut stream.take(LINGER_BYTES), &mut io::sink());
+}
+
+/// Sets the read timeout so that the next read wakes up no later than the
+/// deadline, failing straight away if it has already passed.
+fn arm_read_timeout<S: Connection>(
+    stream: &S,
+    deadline: Option<Instant>,
+    read_timeout: Duration,
+) -> Result<()> {
+    let timeout = match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(RequestError::Timeout.into());
+            }
+            remaining.min(read_timeout)
+        }
+        None => read_timeout,
+    };
+    stream.set_read_timeout(Some(timeout))?;
+    Ok(())
+}
+
+/// Read timeouts surface as `WouldBlock` on Unix and `TimedOut` on Windows.
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// A read timed out, blame the client if it ran out of its overall budget.
+fn timed_out(deadline: Option<Instant>, reading: &'static str) -> anyhow::Error {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => RequestError::Timeout.into(),
+        _ => RequestError::Idle(reading).into(),
+    }
+}
+
+/// Polls the modification time of the store file, raising the flag when it
+/// changes. Only the flag is raised here, the reload runs under the write
+/// lock on the store ahead of the next request. A fetched store has no file
+/// to go by, so the flag is raised every time, see `reload_if_requested`.
+fn watch_store(path: Option<PathBuf>, interval: Duration, store_changed: Arc<AtomicBool>) {
+    let modified = || {
+        let path = path.as_ref()?;
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    };
+    let mut last_modified = modified();
+    loop {
+        thread::sleep(interval);
+        let current = modified();
+        if path.is_none() || current != last_modified {
+            last_modified = current;
+            store_changed.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// First file descriptor handed over under systemd socket activation.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Adopts the listening socket passed in by systemd socket activation, if any.
+///
+/// systemd sets `LISTEN_PID` to our pid and `LISTEN_FDS` to the number of
+/// descriptors, which start at fd 3. Only the first one is used.
+#[cfg(unix)]
+fn inherited_listener() -> Option<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let listen_pid = std::env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    if listen_pid != std::process::id() {
+        return None;
+    }
+    let listen_fds = std::env::var("LISTEN_FDS").ok()?.parse::<i32>().ok()?;
+    // the variables are meant for us alone, not for anything we might spawn
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if listen_fds < 1 {
+        return None;
+    }
+    // SAFETY: systemd guarantees the descriptor is open and owned by this
+    // process once LISTEN_PID matches, and nothing else has claimed it.
+    Some(unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+fn inherited_listener() -> Option<TcpListener> {
+    None
 }
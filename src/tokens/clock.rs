@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::token_store::epoch_now;
+
+/// Where the store gets the time from when deciding expiry and stamping
+/// revocations, so that tests need not wait on the real one.
+pub trait Clock: Send + Sync {
+    /// Seconds since the epoch, if the clock is not set before it.
+    fn now(&self) -> Option<u64>;
+}
+
+/// The system clock, used unless another is given.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Option<u64> {
+        epoch_now()
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct MockClock(AtomicU64);
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        MockClock(AtomicU64::new(now))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.0.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Option<u64> {
+        Some(self.0.load(Ordering::SeqCst))
+    }
+}
@@ -1,2 +1,13 @@
-mod token;
+pub mod clock;
+pub mod remote_source;
+pub mod scope;
+pub mod secret_generator;
+pub mod signing_key;
+pub mod store_diff;
+pub mod store_error;
+pub mod store_format;
+pub mod store_fsck;
+pub mod store_import;
+pub mod store_key;
+pub mod token;
 pub mod token_store;
@@ -0,0 +1,58 @@
+use std::{io::Read, time::Duration};
+
+use super::store_error::TokenStoreError;
+
+/// Time allowed to fetch the token list, so that a hung config service
+/// cannot hold up a reload indefinitely.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest token list fetched, anything bigger is not one of ours.
+const MAX_FETCH_BYTES: u64 = 16 * 1024 * 1024;
+
+/// A token list served over HTTP, laid out as the store file would be. The
+/// store fetches it in place of reading the file, and is read only.
+pub struct RemoteSource {
+    url: String,
+    /// Value of the Authorization header sent along, e.g. `Bearer <token>`.
+    authorization: Option<String>,
+}
+
+impl RemoteSource {
+    /// A source for locations given as an `http://` or `https://` URL.
+    pub fn from_location(location: &str, authorization: Option<String>) -> Option<Self> {
+        let is_url = ["http://", "https://"]
+            .iter()
+            .any(|scheme| location.starts_with(scheme));
+        is_url.then(|| RemoteSource {
+            url: location.to_string(),
+            authorization,
+        })
+    }
+
+    pub fn fetch(&self) -> Result<Vec<u8>, TokenStoreError> {
+        let fetch_error = |e: &dyn std::fmt::Display| {
+            TokenStoreError::Fetch(format!("Unable to fetch {}: {}", self.url, e))
+        };
+        let mut request = ureq::get(&self.url).timeout(FETCH_TIMEOUT);
+        if let Some(authorization) = self.authorization.as_deref() {
+            request = request.set("Authorization", authorization);
+        }
+        // ureq names the URL in its own errors
+        let response = request
+            .call()
+            .map_err(|e| TokenStoreError::Fetch(format!("Unable to fetch token list: {}", e)))?;
+        let mut data = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_FETCH_BYTES + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| fetch_error(&e))?;
+        if data.len() as u64 > MAX_FETCH_BYTES {
+            return Err(fetch_error(&format!(
+                "token list is over {} bytes",
+                MAX_FETCH_BYTES
+            )));
+        }
+        Ok(data)
+    }
+}
@@ -0,0 +1,65 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// A method and path a token may be used for, written as `METHOD PATH`.
+/// `*` stands for any method, and the path covers everything beneath it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Scope {
+    method: String,
+    path: String,
+}
+
+impl Scope {
+    /// Checks a request against the scope, ignoring any query string.
+    pub fn allows(&self, method: &str, uri: &str) -> bool {
+        if self.method != "*" && !self.method.eq_ignore_ascii_case(method) {
+            return false;
+        }
+        let path = uri.split(['?', '#']).next().unwrap_or_default();
+        match path.strip_prefix(self.path.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.path.ends_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((method, path)) = s.trim().split_once(' ') else {
+            return Err(anyhow!("Scopes must be given as METHOD PATH"));
+        };
+        let path = path.trim();
+        if method.is_empty() || !path.starts_with('/') {
+            return Err(anyhow!("Scopes must be given as METHOD PATH"));
+        }
+        Ok(Scope {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for Scope {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        scope.to_string()
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.method, self.path)
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use clap::ValueEnum;
+use uuid::Uuid;
+
+use super::signing_key::to_hex;
+use crate::base64;
+
+/// Random bytes drawn for hex and base64 secrets unless told otherwise.
+pub const DEFAULT_SECRET_BYTES: usize = 16;
+
+/// Where the store gets the secrets of new tokens from, so that tests can
+/// assert on them.
+pub trait SecretGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+/// Random v4 UUIDs, used unless another generator is given.
+pub struct UuidGenerator;
+
+impl SecretGenerator for UuidGenerator {
+    fn generate(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// How generated secrets are written out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SecretFormat {
+    /// A hyphenated v4 UUID, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+    #[default]
+    Uuid,
+    /// Random bytes as lowercase hex, without hyphens.
+    Hex,
+    /// Random bytes as URL-safe base64, without padding.
+    Base64,
+}
+
+/// Secrets in one of the formats above, drawn from the operating system's
+/// random number generator.
+pub struct RandomGenerator {
+    format: SecretFormat,
+    /// Only hex and base64 secrets are of a chosen length.
+    bytes: usize,
+}
+
+impl RandomGenerator {
+    pub fn new(format: SecretFormat, bytes: usize) -> Self {
+        RandomGenerator { format, bytes }
+    }
+}
+
+impl SecretGenerator for RandomGenerator {
+    fn generate(&self) -> String {
+        if self.format == SecretFormat::Uuid {
+            return UuidGenerator.generate();
+        }
+        let mut bytes = vec![0; self.bytes];
+        OsRng.fill_bytes(&mut bytes);
+        match self.format {
+            SecretFormat::Base64 => base64::encode_url(&bytes),
+            _ => to_hex(&bytes),
+        }
+    }
+}
+
+/// Hands out `<prefix>-1`, `<prefix>-2` and so on, the same every run.
+pub struct SequenceGenerator {
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl SequenceGenerator {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        SequenceGenerator {
+            prefix: prefix.into(),
+            next: AtomicU64::new(1),
+        }
+    }
+}
+
+impl SecretGenerator for SequenceGenerator {
+    fn generate(&self) -> String {
+        format!(
+            "{}-{}",
+            self.prefix,
+            self.next.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+}
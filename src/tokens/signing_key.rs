@@ -0,0 +1,59 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Key that signed tokens are issued and verified with. A signed token is
+/// `label.signature`, so nothing secret needs to be kept in the store.
+pub struct SigningKey(Vec<u8>);
+
+impl SigningKey {
+    pub fn new(secret: &[u8]) -> Result<Self> {
+        if secret.is_empty() {
+            return Err(anyhow!("Signing key must not be empty"));
+        }
+        Ok(SigningKey(secret.to_vec()))
+    }
+
+    /// Short fingerprint of the key, recorded against the tokens it signs so
+    /// that they stop verifying once the key is changed.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"mellon signing key id\0");
+        hasher.update(&self.0);
+        to_hex(&hasher.finalize()[..8])
+    }
+
+    pub fn sign(&self, label: &str) -> String {
+        to_hex(&self.mac(label).finalize().into_bytes())
+    }
+
+    /// Checks a signature in constant time.
+    pub fn verify(&self, label: &str, signature: &str) -> bool {
+        match from_hex(signature) {
+            Some(signature) => self.mac(label).verify_slice(&signature).is_ok(),
+            None => false,
+        }
+    }
+
+    fn mac(&self, label: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC takes keys of any length");
+        mac.update(label.as_bytes());
+        mac
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::{anyhow, Result};
+
+use super::store_error::TokenStoreError;
+use super::store_format::Entry;
+use super::token::{hash_secret, MatchKind, Token};
+use super::token_store::{TokenOptions, TokenStore};
+
+/// A token as it should be, according to a desired-state file.
+pub struct DesiredToken {
+    pub label: String,
+    /// When absent, any secret will do and a new one is generated if needed.
+    pub secret: Option<String>,
+}
+
+impl DesiredToken {
+    /// Reads a desired-state file: one bare label or store entry per line,
+    /// blank lines and `#` comments are skipped.
+    pub fn parse_all(contents: &str) -> Result<Vec<DesiredToken>> {
+        let mut desired = Vec::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if !line.contains(':') && !line.starts_with('{') {
+                desired.push(DesiredToken {
+                    label: line.to_string(),
+                    secret: None,
+                });
+                continue;
+            }
+            let entry = Entry::parse(line)
+                .map_err(|_| anyhow!("Failed to parse desired token from line: {}", line))?;
+            desired.push(match entry {
+                Entry::Token(token) => DesiredToken {
+                    label: token.label,
+                    secret: Some(token.secret),
+                },
+                Entry::Tombstone(tombstone) => DesiredToken {
+                    label: tombstone.label,
+                    secret: None,
+                },
+            });
+        }
+        Ok(desired)
+    }
+}
+
+/// What it takes to bring a store in line with a desired state.
+/// Every list is sorted by label so the output is stable.
+#[derive(Default)]
+pub struct StoreDiff {
+    pub to_add: Vec<DesiredToken>,
+    /// Labels present on both sides whose secret differs from the one desired.
+    pub to_update: Vec<DesiredToken>,
+    pub to_rescind: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl StoreDiff {
+    pub fn between(token_store: &TokenStore, desired: Vec<DesiredToken>) -> Result<StoreDiff> {
+        let mut desired: BTreeMap<String, DesiredToken> = desired
+            .into_iter()
+            .map(|token| (token.label.clone(), token))
+            .collect();
+        let mut diff = StoreDiff::default();
+        let mut current: Vec<_> = token_store.iter()?.collect();
+        current.sort_by(|a, b| a.label.cmp(&b.label));
+        for token in current {
+            match desired.remove(&token.label) {
+                None => diff.to_rescind.push(token.label.clone()),
+                Some(wanted) => match wanted.secret.as_ref() {
+                    Some(secret) if !token.holds_secret(secret) => diff.to_update.push(wanted),
+                    _ => diff.unchanged.push(token.label.clone()),
+                },
+            }
+        }
+        diff.to_add = desired.into_values().collect();
+        diff.to_update.sort_by(|a, b| a.label.cmp(&b.label));
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to_add.is_empty() && self.to_update.is_empty() && self.to_rescind.is_empty()
+    }
+
+    /// Applies the diff, returning any secrets generated for added tokens.
+    /// Every change is written to the store file in one go, so that a diff
+    /// that cannot be applied in full leaves the store as it was.
+    pub fn apply(&self, token_store: &mut TokenStore) -> Result<Vec<(String, String)>> {
+        let mut tokens: BTreeMap<String, Token> = token_store
+            .iter()?
+            .map(|token| (token.label.clone(), token.clone()))
+            .collect();
+        for label in self.to_rescind.iter() {
+            tokens.remove(label);
+        }
+        for wanted in self.to_update.iter() {
+            let secret = wanted.secret.as_deref().unwrap_or_default();
+            let Some(token) = tokens.get_mut(&wanted.label) else {
+                continue;
+            };
+            Token::check(&token.label, secret)?;
+            token_store.check_secret(secret)?;
+            token_store.check_lengths(&token.label, secret)?;
+            // only the secret changes, everything else about the token is kept
+            token.secret = match token.matching {
+                MatchKind::Hashed => hash_secret(secret),
+                _ => secret.to_string(),
+            };
+        }
+        let mut generated = Vec::new();
+        for wanted in self.to_add.iter() {
+            let token = match wanted.secret.as_ref() {
+                Some(secret) => {
+                    token_store.check_label(&wanted.label)?;
+                    token_store.check_secret(secret)?;
+                    token_store.check_lengths(&wanted.label, secret)?;
+                    Token::new(&wanted.label, secret)?
+                }
+                None => {
+                    let token = token_store.generate(&wanted.label, TokenOptions::default())?;
+                    generated.push((token.label.clone(), token.secret.clone()));
+                    token
+                }
+            };
+            let token = token_store.conceal(Token {
+                created_at: token_store.now(),
+                ..token
+            });
+            tokens.insert(token.label.clone(), token);
+        }
+        // no two tokens may be let in by the same secret, hashed or not
+        let mut held = HashSet::new();
+        for token in tokens.values() {
+            let hashed = match token.matching {
+                MatchKind::Exact => hash_secret(&token.secret),
+                MatchKind::Hashed => token.secret.clone(),
+                MatchKind::Regex | MatchKind::Signed => continue,
+            };
+            if !held.insert(hashed) {
+                return Err(TokenStoreError::DuplicateSecret.into());
+            }
+        }
+        token_store.replace_tokens(tokens.into_values().collect())?;
+        Ok(generated)
+    }
+}
@@ -0,0 +1,77 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+
+/// Ways the token store can fail, for callers that need to tell them apart.
+#[derive(Debug, Error)]
+pub enum TokenStoreError {
+    #[error("Token store not yet loaded")]
+    NotLoaded,
+    /// Carries the label already taken.
+    #[error("Labels must be unique!")]
+    DuplicateLabel(String),
+    #[error("Secrets must be unique!")]
+    DuplicateSecret,
+    #[error("Secrets must not be empty!")]
+    EmptySecret,
+    #[error("Labels must not be empty!")]
+    EmptyLabel,
+    /// A colon would end the label early in a store file of the colon
+    /// format, the jsonl format holds any label.
+    #[error("Labels must not contain ':' unless the store format is jsonl!")]
+    ColonInLabel,
+    /// The colon format would read the secret back as something else: cut
+    /// short at `:{`, as a tombstone when it starts with a colon, or trimmed.
+    #[error("Secrets must not contain ':{{', start with ':' or start or end with whitespace unless the store format is jsonl!")]
+    UnreadableSecret,
+    /// A line break would split the entry over two lines of the store file.
+    #[error("Labels and secrets must not contain line breaks!")]
+    LineBreak,
+    /// Carries the longest label allowed, in bytes.
+    #[error("Labels must be at most {0} bytes long!")]
+    LabelTooLong(usize),
+    /// Carries the longest secret allowed, in bytes.
+    #[error("Secrets must be at most {0} bytes long!")]
+    SecretTooLong(usize),
+    /// Carries the label that was not found.
+    #[error("No token associated with key!")]
+    NotFound(String),
+    #[error("No token has that secret!")]
+    UnknownSecret,
+    /// Patterns and signed tokens have no secret of their own to replace,
+    /// carries the label of the token.
+    #[error("Only tokens with a secret of their own can be rotated!")]
+    NotRotatable(String),
+    /// Carries the label of the pattern token the secret matched.
+    #[error("The secret only matches the pattern of {0}, rescind that by label if intended.")]
+    PatternOnly(String),
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// The store file, or an entry in it, could not be read or written out.
+    #[error("{0}")]
+    Parse(String),
+    /// The file at the store path is not one we wrote, so it is left alone.
+    #[error("{} does not look like a token store, refusing to write over it. Run `mellon store migrate` on stores from earlier versions.", .0.display())]
+    NotAStore(PathBuf),
+    /// The store is fetched over HTTP and cannot be written to.
+    #[error("Token store at {} is fetched, not written, so it is read only", .0.display())]
+    ReadOnly(PathBuf),
+    /// The token list could not be fetched.
+    #[error("{0}")]
+    Fetch(String),
+    /// The store could not be encrypted or decrypted.
+    #[error("{0}")]
+    Key(String),
+}
+
+impl TokenStoreError {
+    /// Wraps an I/O error with what was being done at the time.
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| TokenStoreError::Io { context, source }
+    }
+}
@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use super::store_error::TokenStoreError;
+use super::token::{Token, Tombstone};
+
+/// How entries are laid out in the store file, one per line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreFormat {
+    /// `label:secret`
+    #[default]
+    Colon,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+impl StoreFormat {
+    /// Tells which format a store file was written in from its first line.
+    pub fn detect(contents: &str) -> Option<StoreFormat> {
+        let line = contents.lines().next()?;
+        match line.trim_start().starts_with('{') {
+            true => Some(StoreFormat::Jsonl),
+            false => Some(StoreFormat::Colon),
+        }
+    }
+
+    /// Whether a label can be written out, the colon format ends a label at
+    /// its first colon.
+    pub fn holds_label(&self, label: &str) -> bool {
+        match self {
+            StoreFormat::Colon => !label.contains(':'),
+            StoreFormat::Jsonl => true,
+        }
+    }
+
+    /// Whether a secret reads back as written. The colon format takes what
+    /// follows `:{` for the token's attributes, a line with a secret of
+    /// `:digits` for a tombstone, and trims the secret.
+    pub fn holds_secret(&self, secret: &str) -> bool {
+        match self {
+            StoreFormat::Colon => {
+                !secret.contains(":{") && !secret.starts_with(':') && secret.trim() == secret
+            }
+            StoreFormat::Jsonl => true,
+        }
+    }
+
+    pub fn token_line(&self, token: &Token) -> Result<String> {
+        if !self.holds_label(&token.label) {
+            return Err(TokenStoreError::ColonInLabel.into());
+        }
+        if !self.holds_secret(&token.secret) {
+            return Err(TokenStoreError::UnreadableSecret.into());
+        }
+        match self {
+            StoreFormat::Colon => Ok(token.to_string()),
+            StoreFormat::Jsonl => Ok(serde_json::to_string(token)?),
+        }
+    }
+
+    pub fn tombstone_line(&self, tombstone: &Tombstone) -> Result<String> {
+        if !self.holds_label(&tombstone.label) {
+            return Err(TokenStoreError::ColonInLabel.into());
+        }
+        match self {
+            StoreFormat::Colon => Ok(tombstone.to_string()),
+            StoreFormat::Jsonl => Ok(serde_json::to_string(tombstone)?),
+        }
+    }
+}
+
+/// A single line of the store file.
+pub enum Entry {
+    Token(Token),
+    Tombstone(Tombstone),
+}
+
+impl Entry {
+    /// Parses a line written in either format, sniffing which one it is.
+    pub fn parse(line: &str) -> Result<Entry> {
+        if line.trim_start().starts_with('{') {
+            return Self::parse_json(line);
+        }
+        // tombstones are tried first, a token line would never parse as one
+        if let Ok(tombstone) = Tombstone::from_str(line) {
+            return Ok(Entry::Tombstone(tombstone));
+        }
+        Token::from_str(line).map(Entry::Token)
+    }
+
+    fn parse_json(line: &str) -> Result<Entry> {
+        let value: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| anyhow!("Unable to parse JSON store entry: {}", e))?;
+        let has_secret = value
+            .get("secret")
+            .and_then(|secret| secret.as_str())
+            .is_some_and(|secret| !secret.is_empty());
+        let entry = match has_secret {
+            true => {
+                let token: Token = serde_json::from_value(value)?;
+                Token::check(&token.label, &token.secret)?;
+                Entry::Token(token)
+            }
+            false => Entry::Tombstone(serde_json::from_value(value)?),
+        };
+        Ok(entry)
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use anyhow::Result;
+
+use super::store_format::Entry;
+use super::token::{MatchKind, Token};
+use super::token_store::TokenStore;
+
+/// A problem found in the store file, along with what a fix does about it.
+/// Loading the store would otherwise resolve these by silently dropping data.
+pub enum Finding {
+    /// A later entry reuses a label, it is kept under a new one.
+    DuplicateLabel {
+        line: usize,
+        label: String,
+        renamed_to: String,
+    },
+    /// A later entry reuses the secret of another, it is dropped.
+    DuplicateSecret {
+        line: usize,
+        label: String,
+        kept_label: String,
+    },
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Finding::DuplicateLabel {
+                line,
+                label,
+                renamed_to,
+            } => write!(
+                f,
+                "line {}: label {} is used more than once, renaming it to {}",
+                line, label, renamed_to
+            ),
+            Finding::DuplicateSecret {
+                line,
+                label,
+                kept_label,
+            } => write!(
+                f,
+                "line {}: token {} shares its secret with {}, dropping it",
+                line, label, kept_label
+            ),
+        }
+    }
+}
+
+/// The outcome of checking a store, and the tokens it would be repaired to.
+pub struct StoreCheck {
+    pub findings: Vec<Finding>,
+    repaired: Vec<Token>,
+}
+
+impl StoreCheck {
+    /// Walks the store file in order. The first entry holding a label or a
+    /// secret always keeps it, so repairs come out the same every run.
+    pub fn run(token_store: &TokenStore) -> Result<StoreCheck> {
+        let entries = token_store.read_entries()?;
+        // the marker line comes ahead of the entries, when there is one
+        let first_line = 1 + usize::from(token_store.layout()?.marked);
+        let mut taken_labels: HashSet<String> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Token(token) => Some(token.label.clone()),
+                Entry::Tombstone(_) => None,
+            })
+            .collect();
+        let mut seen_labels = HashSet::new();
+        let mut seen_secrets: HashMap<String, String> = HashMap::new();
+        let mut check = StoreCheck {
+            findings: Vec::new(),
+            repaired: Vec::new(),
+        };
+        for (index, entry) in entries.into_iter().enumerate() {
+            let Entry::Token(mut token) = entry else {
+                continue;
+            };
+            let line = index + first_line;
+            // every signed token records the same key id in place of a secret
+            let signed = token.matching == MatchKind::Signed;
+            if let Some(kept_label) = seen_secrets.get(&token.secret).filter(|_| !signed) {
+                check.findings.push(Finding::DuplicateSecret {
+                    line,
+                    label: token.label,
+                    kept_label: kept_label.clone(),
+                });
+                continue;
+            }
+            if !seen_labels.insert(token.label.clone()) {
+                let renamed_to = free_label(&token.label, &taken_labels);
+                check.findings.push(Finding::DuplicateLabel {
+                    line,
+                    label: token.label.clone(),
+                    renamed_to: renamed_to.clone(),
+                });
+                taken_labels.insert(renamed_to.clone());
+                seen_labels.insert(renamed_to.clone());
+                token.label = renamed_to;
+            }
+            if !signed {
+                seen_secrets.insert(token.secret.clone(), token.label.clone());
+            }
+            check.repaired.push(token);
+        }
+        Ok(check)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Rewrites the store file with the repaired tokens.
+    pub fn fix(self, token_store: &mut TokenStore) -> Result<()> {
+        Ok(token_store.replace_tokens(self.repaired)?)
+    }
+}
+
+/// Finds the first `<label>-<n>` not already in use.
+fn free_label(label: &str, taken: &HashSet<String>) -> String {
+    let mut n = 2;
+    loop {
+        let candidate = format!("{}-{}", label, n);
+        if !taken.contains(&candidate) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use super::token::{hash_secret, MatchKind, Token};
+use super::token_store::TokenStore;
+
+/// A `LABEL=SECRET` pair read from a `.env` style file.
+pub struct EnvToken {
+    pub label: String,
+    pub secret: String,
+}
+
+impl EnvToken {
+    /// Reads a `.env` style file. Blank lines and `#` comments are skipped,
+    /// an `export ` prefix is dropped and quotes around the secret removed.
+    pub fn parse_all(contents: &str) -> Result<Vec<EnvToken>> {
+        let mut tokens = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+            let Some((label, secret)) = line.split_once('=') else {
+                return Err(anyhow!("line {}: expected LABEL=SECRET", index + 1));
+            };
+            let label = label.trim();
+            if label.is_empty() || label.contains(':') {
+                return Err(anyhow!("line {}: invalid label {:?}", index + 1, label));
+            }
+            tokens.push(EnvToken {
+                label: label.to_string(),
+                secret: unquote(secret.trim()).to_string(),
+            });
+        }
+        Ok(tokens)
+    }
+}
+
+/// What importing a `.env` file does to the store, by label in file order.
+#[derive(Default)]
+pub struct StoreImport {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    /// Carries the label and why it was left alone.
+    pub skipped: Vec<(String, &'static str)>,
+    tokens: Vec<Token>,
+}
+
+impl StoreImport {
+    /// Works out the import against the store as it stands. Existing labels
+    /// only get the new secret when `replace` is set, and a secret already
+    /// held by another token is never taken over.
+    pub fn plan(token_store: &TokenStore, imported: Vec<EnvToken>, replace: bool) -> Result<Self> {
+        let mut tokens: HashMap<String, Token> = token_store
+            .iter()?
+            .map(|token| (token.label.clone(), token.clone()))
+            .collect();
+        let mut holders: HashMap<String, String> = tokens
+            .values()
+            .filter(|token| token.matching != MatchKind::Signed)
+            .map(|token| (token.secret.clone(), token.label.clone()))
+            .collect();
+        let mut import = StoreImport::default();
+        for EnvToken { label, secret } in imported {
+            if secret.is_empty() {
+                import.skipped.push((label, "secret is empty"));
+                continue;
+            }
+            if token_store.check_lengths(&label, &secret).is_err() {
+                import.skipped.push((label, "label or secret is too long"));
+                continue;
+            }
+            if token_store.check_secret(&secret).is_err() {
+                import
+                    .skipped
+                    .push((label, "secret would not read back in this format"));
+                continue;
+            }
+            // hashed tokens are held under the hash of their secret
+            let holder = holders
+                .get(&secret)
+                .or_else(|| holders.get(&hash_secret(&secret)));
+            match holder {
+                Some(holder) if *holder == label => {
+                    import.skipped.push((label, "already up to date"));
+                    continue;
+                }
+                Some(_) => {
+                    import
+                        .skipped
+                        .push((label, "secret is used by another token"));
+                    continue;
+                }
+                None => {}
+            }
+            match tokens.get_mut(&label) {
+                Some(_) if !replace => {
+                    import.skipped.push((label, "label exists, use --replace"));
+                }
+                Some(token) => {
+                    // only the secret changes, everything else about the token is kept
+                    holders.remove(&token.secret);
+                    token.secret = match token.matching {
+                        MatchKind::Hashed => hash_secret(&secret),
+                        _ => secret,
+                    };
+                    holders.insert(token.secret.clone(), label.clone());
+                    import.updated.push(label);
+                }
+                None => {
+                    let token = token_store
+                        .check_label(&label)
+                        .and_then(|()| Token::new(&label, &secret));
+                    let Ok(token) = token else {
+                        import.skipped.push((label, "label or secret is invalid"));
+                        continue;
+                    };
+                    let token = token_store.conceal(Token {
+                        created_at: token_store.now(),
+                        ..token
+                    });
+                    holders.insert(token.secret.clone(), label.clone());
+                    tokens.insert(label.clone(), token);
+                    import.created.push(label);
+                }
+            }
+        }
+        import.tokens = tokens.into_values().collect();
+        Ok(import)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.updated.is_empty()
+    }
+
+    /// Writes every change to the store file in one go.
+    pub fn apply(self, token_store: &mut TokenStore) -> Result<()> {
+        Ok(token_store.replace_tokens(self.tokens)?)
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|rest| rest.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Prefix written ahead of the nonce so that an encrypted store can be told
+/// apart from a plaintext one without a key.
+const MAGIC: &[u8] = b"MELLON-ENC2\n";
+/// Prefix of stores encrypted under a key that was a bare hash of the key
+/// material. Still read, but rewritten under `MAGIC` on the next change.
+const LEGACY_MAGIC: &[u8] = b"MELLON-ENC1\n";
+const NONCE_LEN: usize = 12;
+
+const HKDF_SALT: &[u8] = b"mellon store key salt";
+const HKDF_INFO: &[u8] = b"mellon store data key";
+
+/// Data key used to encrypt the store file, derived from operator supplied
+/// key material.
+pub struct StoreKey {
+    key: Key,
+    /// What the key was before it was derived with HKDF, for reading older
+    /// stores.
+    legacy: Key,
+}
+
+impl StoreKey {
+    /// Derives the data key with HKDF-SHA256. The key material is expected
+    /// to be a high entropy secret, such as the output of
+    /// `openssl rand -hex 32`. HKDF does nothing to slow down guessing, so a
+    /// memorable passphrase makes for a weak key.
+    pub fn derive(secret: &[u8]) -> Result<Self> {
+        if secret.is_empty() {
+            return Err(anyhow!("Store key must not be empty"));
+        }
+        // HKDF-Extract then a single block of HKDF-Expand, RFC 5869
+        let mut extract =
+            <HmacSha256 as Mac>::new_from_slice(HKDF_SALT).expect("HMAC takes keys of any length");
+        extract.update(secret);
+        let prk = extract.finalize().into_bytes();
+        let mut expand =
+            <HmacSha256 as Mac>::new_from_slice(&prk).expect("HMAC takes keys of any length");
+        expand.update(HKDF_INFO);
+        expand.update(&[1]);
+        let key = expand.finalize().into_bytes();
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"mellon store data key\0");
+        hasher.update(secret);
+        Ok(StoreKey {
+            key,
+            legacy: hasher.finalize(),
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(&self.key);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("Failed to encrypt token store"))?;
+        let mut data = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (key, body) = match (data.strip_prefix(MAGIC), data.strip_prefix(LEGACY_MAGIC)) {
+            (Some(body), _) => (&self.key, body),
+            (_, Some(body)) => (&self.legacy, body),
+            _ => return Err(anyhow!("Token store is not encrypted")),
+        };
+        if body.len() < NONCE_LEN {
+            return Err(anyhow!("Encrypted token store is truncated"));
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let cipher = ChaCha20Poly1305::new(key);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt token store, is the key correct?"))
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || data.starts_with(LEGACY_MAGIC)
+}
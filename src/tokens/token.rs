@@ -1,9 +1,136 @@
-use std::{fmt::Display, str::FromStr};
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
 
 use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Clone)]
-pub struct Token(pub String, pub String);
+use super::scope::Scope;
+use super::signing_key::to_hex;
+use super::store_error::TokenStoreError;
+
+/// How a presented token string is compared against the secret.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    #[default]
+    Exact,
+    /// The secret is a regular expression the whole token string must match.
+    Regex,
+    /// The token string is the label and its signature under the store's
+    /// signing key. The secret only records which key that was.
+    #[value(skip)]
+    Signed,
+    /// The secret is the SHA-256 of the token string in hex, so that the
+    /// store never holds the token string itself.
+    #[value(skip)]
+    Hashed,
+}
+
+impl MatchKind {
+    fn is_exact(&self) -> bool {
+        *self == MatchKind::Exact
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub label: String,
+    pub secret: String,
+    /// Free-form notes for record keeping, never consulted for authorization.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// How many more times the token may be used, unlimited when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_uses: Option<u64>,
+    /// Requests the token may be used for, any request when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<Scope>,
+    /// Seconds since the epoch after which the token is no longer accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Seconds since the epoch when the token was issued, unknown for
+    /// tokens from before this was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// Seconds since the epoch when the token last let a request in, as of
+    /// the last time the server wrote it down.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<u64>,
+    #[serde(default, rename = "match", skip_serializing_if = "MatchKind::is_exact")]
+    pub matching: MatchKind,
+}
+
+impl Token {
+    /// A token for any request, with nothing more to it than its label and
+    /// secret. Tokens created or read by the store all go through here or
+    /// `check`, so that none is written out that would not read back.
+    pub fn new(label: &str, secret: &str) -> Result<Token, TokenStoreError> {
+        Token::check(label, secret)?;
+        Ok(Token {
+            label: label.to_string(),
+            secret: secret.to_string(),
+            annotations: BTreeMap::new(),
+            remaining_uses: None,
+            scopes: Vec::new(),
+            expires_at: None,
+            created_at: None,
+            last_used_at: None,
+            matching: MatchKind::Exact,
+        })
+    }
+
+    /// Checks a label and secret against what the store file can hold in
+    /// either format. Length limits and what only the colon format cannot
+    /// hold are up to the store, see `TokenStore::check_lengths`,
+    /// `TokenStore::check_label` and `TokenStore::check_secret`.
+    pub fn check(label: &str, secret: &str) -> Result<(), TokenStoreError> {
+        if label.is_empty() {
+            return Err(TokenStoreError::EmptyLabel);
+        }
+        if secret.is_empty() {
+            return Err(TokenStoreError::EmptySecret);
+        }
+        if label.contains(['\r', '\n']) || secret.contains(['\r', '\n']) {
+            return Err(TokenStoreError::LineBreak);
+        }
+        Ok(())
+    }
+
+    pub fn allows(&self, method: &str, uri: &str) -> bool {
+        self.scopes.is_empty() || self.scopes.iter().any(|scope| scope.allows(method, uri))
+    }
+
+    /// Whether the token was issued with this secret, which a hashed token
+    /// only knows the hash of.
+    pub fn holds_secret(&self, secret: &str) -> bool {
+        match self.matching {
+            MatchKind::Hashed => self.secret == hash_secret(secret),
+            _ => self.secret == secret,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Compiles the secret of a regex entry, anchored at both ends so that
+    /// it never matches just part of a token string.
+    pub fn pattern(&self) -> Result<Option<Regex>> {
+        match self.matching {
+            MatchKind::Exact | MatchKind::Signed | MatchKind::Hashed => Ok(None),
+            MatchKind::Regex => Regex::new(&format!("^(?:{})$", self.secret))
+                .map(Some)
+                .map_err(|e| anyhow!("Invalid pattern for token {}: {}", self.label, e)),
+        }
+    }
+}
+
+/// What a hashed token keeps in place of its secret.
+pub fn hash_secret(token_string: &str) -> String {
+    to_hex(&Sha256::digest(token_string.as_bytes()))
+}
 
 impl FromStr for Token {
     type Err = anyhow::Error;
@@ -15,16 +142,81 @@ impl FromStr for Token {
                 "Unable to parse token from string! Improperly segmented."
             )); // Replace with a more appropriate error
         }
-        Ok(Token(
-            parts[0].trim().to_string(),
-            parts[1].trim().to_string(),
-        ))
+        let label = parts[0].trim();
+        // anything beyond label and secret trails the secret as a JSON object
+        let Some((secret, extras)) = parts[1].split_once(":{") else {
+            return Ok(Token::new(label, parts[1].trim())?);
+        };
+        let mut record: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&format!("{{{}", extras))
+                .map_err(|_| anyhow!("Unable to parse token attributes!"))?;
+        record.insert("label".to_string(), label.into());
+        record.insert("secret".to_string(), secret.trim().into());
+        let token: Token = serde_json::from_value(record.into())
+            .map_err(|_| anyhow!("Unable to parse token attributes!"))?;
+        Token::check(&token.label, &token.secret)?;
+        Ok(token)
     }
 }
 
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:{}", self.0, self.1)
+        write!(f, "{}:{}", self.label, self.secret)?;
+        let Ok(serde_json::Value::Object(mut extras)) = serde_json::to_value(self) else {
+            return Err(std::fmt::Error);
+        };
+        extras.remove("label");
+        extras.remove("secret");
+        if !extras.is_empty() {
+            write!(f, ":{}", serde_json::Value::Object(extras))?;
+        }
+        Ok(())
+    }
+}
+
+/// What remains of a rescinded token when its history is kept.
+///
+/// Stored as `label::revoked_at`, i.e. a token line with an empty secret
+/// followed by the time of revocation in seconds since the epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub label: String,
+    pub revoked_at: Option<u64>,
+}
+
+impl FromStr for Tombstone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((label, rest)) = s.split_once(':') else {
+            return Err(anyhow!("Unable to parse tombstone from string!"));
+        };
+        let revoked_at = match rest.trim() {
+            "" => None,
+            rest => {
+                let Some(revoked_at) = rest.strip_prefix(':') else {
+                    return Err(anyhow!("Not a tombstone, secret is present."));
+                };
+                Some(
+                    revoked_at
+                        .trim()
+                        .parse::<u64>()
+                        .map_err(|_| anyhow!("Unable to parse revocation time of tombstone!"))?,
+                )
+            }
+        };
+        Ok(Tombstone {
+            label: label.trim().to_string(),
+            revoked_at,
+        })
     }
 }
 
+impl Display for Tombstone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.revoked_at {
+            Some(revoked_at) => write!(f, "{}::{}", self.label, revoked_at),
+            None => write!(f, "{}:", self.label),
+        }
+    }
+}
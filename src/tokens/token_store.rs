@@ -1,129 +1,1374 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::ErrorKind;
-use std::io::{self, BufRead, Write};
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use super::token::Token;
-use anyhow::{anyhow, Result};
-use uuid::Uuid;
+use super::clock::{Clock, SystemClock};
+use super::remote_source::RemoteSource;
+use super::scope::Scope;
+use super::secret_generator::{SecretGenerator, UuidGenerator};
+use super::signing_key::SigningKey;
+use super::store_error::TokenStoreError;
+use super::store_format::{Entry, StoreFormat};
+use super::store_key::{is_encrypted, StoreKey};
+use super::token::{hash_secret, MatchKind, Token, Tombstone};
+use regex::Regex;
+
+type Result<T> = std::result::Result<T, TokenStoreError>;
 
 pub struct TokenStore {
     file_path: PathBuf,
     tokens: Option<HashMap<String, Token>>, // Stores all token objects in memory
-    token_lookup: Option<HashSet<String>>,  // Quickly checks if a token string is authorized
+    token_lookup: Option<HashMap<String, String>>, // Maps authorized token strings to their label
+    hashed_lookup: HashMap<String, String>, // Maps hashes of token strings to their label
+    patterns: Vec<(Regex, String)>,         // Regex entries and their label, tried after the lookup
+    tombstones: Vec<Tombstone>,             // Rescinded tokens kept for the record
+    usage: Mutex<Usage>,                    // Remaining uses and last uses, ahead of the file
+    persisting: Mutex<()>,                  // Held while writing, workers may flush at once
+    loaded_format: Option<StoreFormat>,     // Format the file was in when last fully loaded
+    remote: Option<RemoteSource>,           // Fetched from instead of the file, read only
+    options: StoreOptions,
+}
+
+/// Remaining uses are counted down in memory and only written out now and
+/// then, writing the whole file on every request would be far too costly.
+/// When tokens were last used is kept the same way.
+#[derive(Default)]
+struct Usage {
+    remaining: HashMap<String, u64>,
+    last_used: HashMap<String, u64>,
+    /// Uses counted since the file last caught up, taken off whatever the
+    /// file says when it is read again.
+    unflushed: HashMap<String, u64>,
+    last_flushed: Option<Instant>,
+    /// Set once a flush has found the file will not be written to, so that
+    /// it is only said the once.
+    unwritable: bool,
+}
+
+/// The outcome of presenting a token string.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TokenUse {
+    /// Carries the label of the token.
+    Allowed(String),
+    /// The token is known but has no uses left.
+    Exhausted(String),
+    /// The token is known but past its expiry.
+    Expired(String),
+    Unknown,
+}
+
+/// What a reload did to the tokens held in memory.
+pub enum Reload {
+    /// The store was rebuilt from scratch.
+    Full,
+    /// Only the entries that differ were touched, listed by label.
+    Incremental {
+        added: Vec<String>,
+        updated: Vec<String>,
+        removed: Vec<String>,
+    },
+}
+
+/// How the store file is laid out, telling stores written by earlier
+/// versions apart from current ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreLayout {
+    /// Whether the file starts with the store marker.
+    pub marked: bool,
+    /// Format of the entries, none when there are none.
+    pub format: Option<StoreFormat>,
+}
+
+/// Optional settings for a token being created.
+#[derive(Default, Clone)]
+pub struct TokenOptions {
+    pub annotations: BTreeMap<String, String>,
+    /// Number of times the token may be used before it is rejected.
+    pub max_uses: Option<u64>,
+    pub scopes: Vec<Scope>,
+    /// Seconds since the epoch after which the token is rejected.
+    pub expires_at: Option<u64>,
+    pub matching: MatchKind,
+}
+
+/// How long counted down uses may go unwritten.
+const USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the last use of a token may go unwritten, only stale tokens
+/// are looked for by it so it can lag well behind.
+const LAST_USED_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of times a write of the store is attempted before giving up.
+const PERSIST_ATTEMPTS: u32 = 3;
+
+/// Delay ahead of the first retry of a failed write, doubled for each one after.
+const PERSIST_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// In-memory state from before a mutation, restored if it cannot be persisted.
+struct Snapshot {
+    tokens: Option<HashMap<String, Token>>,
+    tombstones: Vec<Tombstone>,
+}
+
+/// First line of every store file written, so that a file can be told to
+/// be a store before it is written over.
+const STORE_MARKER: &str = "# mellon token store\n";
+
+pub const DEFAULT_MAX_LABEL_LEN: usize = 256;
+pub const DEFAULT_MAX_SECRET_LEN: usize = 1024;
+
+/// How the store is kept on disk.
+pub struct StoreOptions {
+    /// Encrypts the file at rest when present.
+    pub key: Option<StoreKey>,
+    /// Format written back on persist, either format is read.
+    pub format: StoreFormat,
+    /// Longest label, in bytes, a new token may be given.
+    pub max_label_len: usize,
+    /// Longest secret, in bytes, a new token may be given.
+    pub max_secret_len: usize,
+    /// Issues signed tokens instead of storing secrets when present.
+    pub signing_key: Option<SigningKey>,
+    /// Decides expiry and stamps revocations.
+    pub clock: Arc<dyn Clock>,
+    /// Comes up with the secrets of new tokens, random UUIDs by default.
+    pub secret_generator: Arc<dyn SecretGenerator>,
+    /// Writes over the file even when it does not look like a store.
+    pub force_overwrite: bool,
+    /// Authorization header sent when the store is fetched over HTTP.
+    pub remote_authorization: Option<String>,
+    /// Keeps only a hash of the secrets of new exact tokens.
+    pub hash_secrets: bool,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            key: None,
+            format: StoreFormat::default(),
+            max_label_len: DEFAULT_MAX_LABEL_LEN,
+            max_secret_len: DEFAULT_MAX_SECRET_LEN,
+            signing_key: None,
+            clock: Arc::new(SystemClock),
+            secret_generator: Arc::new(UuidGenerator),
+            force_overwrite: false,
+            remote_authorization: None,
+            hash_secrets: false,
+        }
+    }
 }
 
 impl TokenStore {
-    pub fn new(file_path: String) -> Result<Self> {
+    /// Opens the store at `file_path`, which may also be an `http://` or
+    /// `https://` URL to fetch a read only token list from.
+    pub fn new(file_path: String, options: StoreOptions) -> Result<Self> {
+        let remote = RemoteSource::from_location(&file_path, options.remote_authorization.clone());
         let store_path = PathBuf::from(file_path);
-        if let Some(dir_path) = store_path.parent() {
+        if let Some(dir_path) = store_path.parent().filter(|_| remote.is_none()) {
             if !dir_path.exists() {
-                fs::create_dir_all(dir_path).expect("Failed to create directory");
+                fs::create_dir_all(dir_path).map_err(TokenStoreError::io(format!(
+                    "Unable to create store directory {}",
+                    dir_path.display()
+                )))?;
             }
         }
         let mut token_store = TokenStore {
             file_path: store_path,
             tokens: None,
             token_lookup: None,
+            hashed_lookup: HashMap::new(),
+            patterns: Vec::new(),
+            tombstones: Vec::new(),
+            usage: Mutex::new(Usage::default()),
+            persisting: Mutex::new(()),
+            loaded_format: None,
+            remote,
+            options,
         };
-        token_store.reload()?;
+        match token_store.reload() {
+            // the list may well come back, so the store is left unloaded
+            // and requests are dealt with as while it cannot be consulted
+            Err(TokenStoreError::Fetch(e)) => {
+                crate::log!("WARNING: {}, holding no tokens until a reload succeeds", e)
+            }
+            loaded => loaded?,
+        }
         Ok(token_store)
     }
 
     pub fn reload(&mut self) -> Result<()> {
-        let file = match File::open(self.file_path.clone()) {
-            Ok(file) => file,
-            Err(ref error) if error.kind() == ErrorKind::NotFound => {
-                self.tokens = Some(HashMap::new());
-                self.token_lookup = Some(HashSet::new());
-                return Ok(());
-            }
-            Err(_) => {
-                return Err(anyhow!(
-                    "Unable to open keystore file at {}",
-                    self.file_path.display()
-                ))
-            }
-        };
-        let reader = io::BufReader::new(file);
+        let contents = self.read_contents()?;
+        self.load_contents(&contents)
+    }
 
+    /// Rebuilds memory from the entries read out of the store file.
+    fn load_contents(&mut self, contents: &str) -> Result<()> {
         let mut token_map = HashMap::new();
-        for line_result in reader.lines() {
-            let line = line_result.map_err(|e| anyhow!("Failed to read line: {}", e))?;
-            let token = Token::from_str(&line)
-                .map_err(|_| anyhow!("Failed to parse token from line: {}", line))?;
-            token_map.insert(token.0.clone(), token);
+        let mut tombstones = Vec::new();
+        for entry in parse_entries(contents)? {
+            match entry {
+                Entry::Token(token) => {
+                    self.warn_if_oversized(&token);
+                    token_map.insert(token.label.clone(), token);
+                }
+                // tombstones never make it into the map, so they cannot authenticate
+                Entry::Tombstone(tombstone) => tombstones.push(tombstone),
+            }
         }
 
         self.tokens = Some(token_map);
+        self.tombstones = tombstones;
+        self.loaded_format = StoreFormat::detect(contents);
         self.rebuild_token_lookup()?;
         Ok(())
     }
 
-    fn persist_to_file(&self) -> io::Result<()> {
-        let file = File::create(self.file_path.clone())?;
-        let mut writer = io::BufWriter::new(file);
+    /// Brings memory in line with the file by applying only the entries
+    /// that differ, rather than rebuilding every map. Uses counted down in
+    /// memory are kept for tokens left as they were. Falls back to a full
+    /// reload when the file looks to have been replaced wholesale.
+    pub fn refresh(&mut self) -> Result<Reload> {
+        let contents = self.read_contents()?;
+        self.refresh_from(&contents)
+    }
+
+    /// Refreshes from entries read out of the store file beforehand, see
+    /// `read_contents`, so that a slow fetch need not hold up the store.
+    pub fn refresh_from(&mut self, contents: &str) -> Result<Reload> {
+        let entries = parse_entries(contents)?;
+        let held = self.tokens.as_ref().map_or(0, HashMap::len);
+        let format_changed =
+            StoreFormat::detect(contents).is_some_and(|format| Some(format) != self.loaded_format);
+        let token_count = entries
+            .iter()
+            .filter(|entry| matches!(entry, Entry::Token(_)))
+            .count();
+        if self.tokens.is_none() || format_changed || token_count < held / 2 {
+            self.load_contents(contents)?;
+            return Ok(Reload::Full);
+        }
+
+        let (mut added, mut updated) = (Vec::new(), Vec::new());
+        let mut seen = HashSet::new();
+        let mut tombstones = Vec::new();
+        for entry in entries {
+            let token = match entry {
+                Entry::Token(token) => token,
+                Entry::Tombstone(tombstone) => {
+                    tombstones.push(tombstone);
+                    continue;
+                }
+            };
+            seen.insert(token.label.clone());
+            let current = self
+                .tokens
+                .as_ref()
+                .and_then(|tokens| tokens.get(&token.label));
+            match current {
+                Some(current) if same_apart_from_uses(current, &token) => {
+                    self.track_usage(&token);
+                    continue;
+                }
+                Some(_) => updated.push(token.label.clone()),
+                None => added.push(token.label.clone()),
+            }
+            self.warn_if_oversized(&token);
+            self.forget_token(&token.label);
+            self.hold_token(token)?;
+        }
+        let removed: Vec<String> = self
+            .tokens
+            .iter()
+            .flat_map(|tokens| tokens.keys())
+            .filter(|label| !seen.contains(*label))
+            .cloned()
+            .collect();
+        for label in removed.iter() {
+            self.forget_token(label);
+        }
+        self.tombstones = tombstones;
+        Ok(Reload::Incremental {
+            added,
+            updated,
+            removed,
+        })
+    }
+
+    /// Entries already in the file are loaded whatever their length, so
+    /// that tightening the limits never locks anyone out.
+    fn warn_if_oversized(&self, token: &Token) {
+        if let Err(e) = self.check_lengths(&token.label, &token.secret) {
+            crate::log!(
+                "WARNING: token {:.32} in the store is over-long: {}",
+                token.label,
+                e
+            );
+        }
+    }
+
+    /// Checks a new label against what the store format can hold.
+    pub fn check_label(&self, label: &str) -> Result<()> {
+        match self.options.format.holds_label(label) {
+            true => Ok(()),
+            false => Err(TokenStoreError::ColonInLabel),
+        }
+    }
+
+    /// Checks a new secret against what the store format can hold.
+    pub fn check_secret(&self, secret: &str) -> Result<()> {
+        match self.options.format.holds_secret(secret) {
+            true => Ok(()),
+            false => Err(TokenStoreError::UnreadableSecret),
+        }
+    }
+
+    /// Checks a label and secret against the configured limits.
+    pub fn check_lengths(&self, label: &str, secret: &str) -> Result<()> {
+        if label.len() > self.options.max_label_len {
+            return Err(TokenStoreError::LabelTooLong(self.options.max_label_len));
+        }
+        if secret.len() > self.options.max_secret_len {
+            return Err(TokenStoreError::SecretTooLong(self.options.max_secret_len));
+        }
+        Ok(())
+    }
+
+    /// Lookups are only dropped while they still lead to the label, a
+    /// secret that moved to another label in the same refresh may already
+    /// lead there instead.
+    fn forget_token(&mut self, label: &str) {
+        let Some(token) = self.tokens.as_mut().and_then(|tokens| tokens.remove(label)) else {
+            return;
+        };
+        let leads_here = |lookup: &HashMap<String, String>| {
+            lookup.get(&token.secret).is_some_and(|held| held == label)
+        };
+        match token.matching {
+            MatchKind::Exact => {
+                if let Some(token_lookup) = self.token_lookup.as_mut() {
+                    if leads_here(token_lookup) {
+                        token_lookup.remove(&token.secret);
+                    }
+                }
+            }
+            MatchKind::Regex => self.patterns.retain(|(_, held)| held != label),
+            MatchKind::Hashed => {
+                if leads_here(&self.hashed_lookup) {
+                    self.hashed_lookup.remove(&token.secret);
+                }
+            }
+            MatchKind::Signed => {}
+        }
+        let usage = self.usage_mut();
+        usage.remaining.remove(label);
+        usage.last_used.remove(label);
+        usage.unflushed.remove(label);
+    }
+
+    fn hold_token(&mut self, token: Token) -> Result<()> {
+        match token
+            .pattern()
+            .map_err(|e| TokenStoreError::Parse(e.to_string()))?
+        {
+            Some(pattern) => {
+                self.patterns.push((pattern, token.label.clone()));
+                self.patterns.sort_by(|a, b| a.1.cmp(&b.1));
+            }
+            None if token.matching == MatchKind::Signed => {}
+            None if token.matching == MatchKind::Hashed => {
+                self.hashed_lookup
+                    .insert(token.secret.clone(), token.label.clone());
+            }
+            None => {
+                if let Some(token_lookup) = self.token_lookup.as_mut() {
+                    token_lookup.insert(token.secret.clone(), token.label.clone());
+                }
+            }
+        }
+        self.track_usage(&token);
+        if let Some(tokens) = self.tokens.as_mut() {
+            tokens.insert(token.label.clone(), token);
+        }
+        Ok(())
+    }
+
+    /// Takes the uses of a token as read from the file, less those counted
+    /// since the file last caught up.
+    fn track_usage(&mut self, token: &Token) {
+        let usage = self.usage_mut();
+        if let Some(uses) = token.remaining_uses {
+            let counted = usage
+                .unflushed
+                .get(&token.label)
+                .copied()
+                .unwrap_or_default();
+            usage
+                .remaining
+                .insert(token.label.clone(), uses.saturating_sub(counted));
+        }
+        let held = usage.last_used.get(&token.label).copied();
+        if let Some(used) = token.last_used_at.max(held) {
+            usage.last_used.insert(token.label.clone(), used);
+        }
+    }
+
+    /// Reads every entry of the store file in order, duplicates and all.
+    /// A missing file reads as an empty store.
+    pub fn read_entries(&self) -> Result<Vec<Entry>> {
+        parse_entries(&self.read_contents()?)
+    }
+
+    /// Reads the entries of the store file, fetching them for a remote
+    /// store, with the marker line left out.
+    pub fn read_contents(&self) -> Result<String> {
+        let contents = self.read_file()?;
+        // stores written before the marker was introduced go without it
+        match contents.strip_prefix(STORE_MARKER) {
+            Some(entries) => Ok(entries.to_string()),
+            None => Ok(contents),
+        }
+    }
+
+    /// Reads the store file as written, decrypting it when need be.
+    fn read_file(&self) -> Result<String> {
+        let mut data = match self.remote.as_ref() {
+            Some(remote) => remote.fetch()?,
+            None => {
+                let mut file = match File::open(self.file_path.clone()) {
+                    Ok(file) => file,
+                    Err(ref error) if error.kind() == ErrorKind::NotFound => {
+                        return Ok(String::new())
+                    }
+                    Err(error) => {
+                        return Err(TokenStoreError::io(format!(
+                            "Unable to open keystore file at {}",
+                            self.file_path.display()
+                        ))(error))
+                    }
+                };
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)
+                    .map_err(TokenStoreError::io("Failed to read keystore file"))?;
+                data
+            }
+        };
+        if is_encrypted(&data) {
+            let Some(key) = self.options.key.as_ref() else {
+                return Err(TokenStoreError::Key(format!(
+                    "Keystore file at {} is encrypted but no key was supplied",
+                    self.file_path.display()
+                )));
+            };
+            data = key
+                .decrypt(&data)
+                .map_err(|e| TokenStoreError::Key(e.to_string()))?;
+        }
+        String::from_utf8(data)
+            .map_err(|_| TokenStoreError::Parse("Keystore file is not valid UTF-8".to_string()))
+    }
+
+    pub fn layout(&self) -> Result<StoreLayout> {
+        let contents = self.read_file()?;
+        let entries = contents.strip_prefix(STORE_MARKER);
+        Ok(StoreLayout {
+            marked: entries.is_some(),
+            format: StoreFormat::detect(entries.unwrap_or(&contents)),
+        })
+    }
+
+    /// Whether the file is already laid out as it would be written now.
+    /// A file without entries has nothing to migrate.
+    pub fn is_current(&self) -> Result<bool> {
+        let layout = self.layout()?;
+        Ok(match layout.format {
+            Some(format) => layout.marked && format == self.options.format,
+            None => true,
+        })
+    }
+
+    /// Rewrites the file as it would be written now, whatever it was laid
+    /// out as before. As migrating is asked for outright the file is
+    /// written over even when it is not marked as a store.
+    pub fn migrate(&mut self) -> Result<()> {
+        if self.tokens.is_none() {
+            return Err(TokenStoreError::NotLoaded);
+        }
+        let force_overwrite = std::mem::replace(&mut self.options.force_overwrite, true);
+        let persisted = self.persist_with_retry();
+        self.options.force_overwrite = force_overwrite;
+        persisted
+    }
+
+    /// Refuses to write over a file that is neither empty nor a store, which
+    /// is more likely a mistyped path than anything we wrote. Encrypted
+    /// stores carry a marker of their own.
+    fn check_overwrite(&self) -> Result<()> {
+        if self.remote.is_some() {
+            return Err(TokenStoreError::ReadOnly(self.file_path.clone()));
+        }
+        if self.options.force_overwrite {
+            return Ok(());
+        }
+        let read_error = || {
+            format!(
+                "Unable to read keystore file at {}",
+                self.file_path.display()
+            )
+        };
+        let file = match File::open(&self.file_path) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(TokenStoreError::io(read_error())(error)),
+        };
+        let mut head = Vec::new();
+        file.take(STORE_MARKER.len() as u64)
+            .read_to_end(&mut head)
+            .map_err(TokenStoreError::io(read_error()))?;
+        if head.is_empty() || head == STORE_MARKER.as_bytes() || is_encrypted(&head) {
+            return Ok(());
+        }
+        Err(TokenStoreError::NotAStore(self.file_path.clone()))
+    }
+
+    /// Persists the store, retrying with backoff since network and busy
+    /// filesystems can fail transiently.
+    fn persist_with_retry(&self) -> Result<()> {
+        self.retrying(|| self.persist_to_file())
+    }
+
+    /// Writes the uses counted in memory into the file as it is now, see
+    /// `persist_usage`.
+    fn persist_usage_with_retry(&self) -> Result<()> {
+        self.retrying(|| self.persist_usage())
+    }
+
+    /// Runs a write of the store until it succeeds or the attempts run
+    /// out. Only I/O errors are tried again, the rest would fail alike on
+    /// every attempt. Writers in this process and others take turns, so that none
+    /// reads the file while another is about to replace it.
+    fn retrying(&self, persist: impl Fn() -> Result<()>) -> Result<()> {
+        let _persisting = self.persisting.lock().unwrap_or_else(|e| e.into_inner());
+        let _locked = match self.remote.is_none() {
+            true => Some(lock_dir(&self.target_path())?),
+            false => None,
+        };
+        let mut delay = PERSIST_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match persist() {
+                Ok(()) => return Ok(()),
+                Err(TokenStoreError::Io { .. }) if attempt < PERSIST_ATTEMPTS => {
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Also brings the tokens held up to date with the uses counted, so
+    /// that the change goes ahead from the uses actually left.
+    fn snapshot(&mut self) -> Snapshot {
+        self.catch_up_usage();
+        Snapshot {
+            tokens: self.tokens.clone(),
+            tombstones: self.tombstones.clone(),
+        }
+    }
+
+    /// Writes out a mutation made since the snapshot was taken. Should that
+    /// fail, the mutation is undone so that memory and disk keep agreeing.
+    fn commit(&mut self, snapshot: Snapshot) -> Result<()> {
+        self.rebuild_token_lookup()?;
+        if let Err(e) = self.persist_with_retry() {
+            self.tokens = snapshot.tokens;
+            self.tombstones = snapshot.tombstones;
+            self.rebuild_token_lookup()?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Writes out every token held in memory, for changes made to the
+    /// store itself. Uses counted in memory go along with them.
+    fn persist_to_file(&self) -> Result<()> {
+        self.check_overwrite()?;
+        let mut contents = STORE_MARKER.as_bytes().to_vec();
         if let Some(tokens) = self.tokens.as_ref() {
+            let usage = self.usage();
+            let (remaining, last_used) = (usage.remaining.clone(), usage.last_used.clone());
+            drop(usage);
             for token in tokens.values() {
-                writeln!(writer, "{}", token)?;
+                let line = match (remaining.get(&token.label), last_used.get(&token.label)) {
+                    (None, None) => self.options.format.token_line(token),
+                    (uses, used) => {
+                        let mut token = token.clone();
+                        token.remaining_uses = uses.copied().or(token.remaining_uses);
+                        token.last_used_at = used.copied().or(token.last_used_at);
+                        self.options.format.token_line(&token)
+                    }
+                };
+                let line = line.map_err(|e| TokenStoreError::Parse(e.to_string()))?;
+                contents.extend_from_slice(line.as_bytes());
+                contents.push(b'\n');
+            }
+        }
+        for tombstone in self.tombstones.iter() {
+            let line = self
+                .options
+                .format
+                .tombstone_line(tombstone)
+                .map_err(|e| TokenStoreError::Parse(e.to_string()))?;
+            contents.extend_from_slice(line.as_bytes());
+            contents.push(b'\n');
+        }
+        self.write_store(contents)?;
+        self.usage().unflushed.clear();
+        Ok(())
+    }
+
+    /// Writes the uses counted in memory into the file as it is now, and
+    /// nothing else. Tokens added or rescinded since the store was read
+    /// stay as the file has them, and a token given a new secret meanwhile
+    /// is no longer the one the uses were counted against.
+    fn persist_usage(&self) -> Result<()> {
+        if !self.file_path.exists() {
+            return Ok(());
+        }
+        self.check_overwrite()?;
+        let entries = self.read_contents()?;
+        let format = StoreFormat::detect(&entries).unwrap_or(self.options.format);
+        let mut usage = self.usage();
+        let unflushed = std::mem::take(&mut usage.unflushed);
+        let last_used = usage.last_used.clone();
+        drop(usage);
+        let mut contents = STORE_MARKER.as_bytes().to_vec();
+        let mut written = Ok(());
+        for entry in parse_entries(&entries)? {
+            let line = match entry {
+                Entry::Token(mut token) => {
+                    let held = self.get(&token.label).ok().flatten();
+                    if held.is_some_and(|held| held.secret == token.secret) {
+                        let counted = unflushed.get(&token.label).copied().unwrap_or_default();
+                        token.remaining_uses = token
+                            .remaining_uses
+                            .map(|uses| uses.saturating_sub(counted));
+                        token.last_used_at =
+                            token.last_used_at.max(last_used.get(&token.label).copied());
+                    }
+                    format.token_line(&token)
+                }
+                Entry::Tombstone(tombstone) => format.tombstone_line(&tombstone),
+            };
+            match line {
+                Ok(line) => {
+                    contents.extend_from_slice(line.as_bytes());
+                    contents.push(b'\n');
+                }
+                Err(e) => {
+                    written = Err(TokenStoreError::Parse(e.to_string()));
+                    break;
+                }
             }
         }
+        let written = written.and_then(|()| self.write_store(contents));
+        if written.is_err() {
+            // counted again on the next attempt
+            let mut usage = self.usage();
+            for (label, counted) in unflushed {
+                *usage.unflushed.entry(label).or_default() += counted;
+            }
+        }
+        written
+    }
+
+    /// The file the store is written to, symlinks followed.
+    fn target_path(&self) -> PathBuf {
+        fs::canonicalize(&self.file_path).unwrap_or_else(|_| self.file_path.clone())
+    }
+
+    /// Encrypts the contents when need be and puts them in place of the
+    /// store file.
+    fn write_store(&self, mut contents: Vec<u8>) -> Result<()> {
+        if let Some(key) = self.options.key.as_ref() {
+            contents = key
+                .encrypt(&contents)
+                .map_err(|e| TokenStoreError::Key(e.to_string()))?;
+        }
+        let write_error = || {
+            format!(
+                "Unable to write keystore file at {}",
+                self.file_path.display()
+            )
+        };
+        // written aside and renamed over the store, so that a crash mid-write
+        // leaves the previous store in place rather than a truncated one
+        let target = self.target_path();
+        let partial = partial_path(&target);
+        let written = write_partial(&partial, &target, &contents)
+            .and_then(|()| fs::rename(&partial, &target));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(TokenStoreError::io(write_error())(e));
+        }
+        Ok(())
+    }
+
+    /// Re-encrypts the store under a new key, or writes it back in plaintext
+    /// when no key is given.
+    pub fn rekey(&mut self, new_key: Option<StoreKey>) -> Result<()> {
+        if self.tokens.is_none() {
+            return Err(TokenStoreError::NotLoaded);
+        }
+        let old_key = std::mem::replace(&mut self.options.key, new_key);
+        if let Err(e) = self.persist_with_retry() {
+            self.options.key = old_key;
+            return Err(e);
+        }
         Ok(())
     }
 
+    /// Whether a token string belongs to a token that has neither expired
+    /// nor been used up.
     pub fn contains_token(&self, token_string: &str) -> Result<bool> {
-        let token_store = self
+        Ok(self.active_token(token_string)?.is_some())
+    }
+
+    /// Finds the token a token string belongs to, if any. Nothing is
+    /// counted against it, see `admit` for that.
+    pub fn authenticate(&self, token_string: &str) -> Result<Option<&Token>> {
+        let Some(tokens) = self.tokens.as_ref() else {
+            return Err(TokenStoreError::NotLoaded);
+        };
+        if let Some(label) = self.label_for(token_string)? {
+            return Ok(tokens.get(label));
+        }
+        Ok(self.verify_signed(token_string))
+    }
+
+    /// Finds the signed token a `label.signature` token string stands for,
+    /// provided the signature holds up under the key it was issued with.
+    fn verify_signed(&self, token_string: &str) -> Option<&Token> {
+        let signing_key = self.options.signing_key.as_ref()?;
+        let (label, signature) = token_string.rsplit_once('.')?;
+        self.tokens
+            .as_ref()?
+            .get(label)
+            .filter(|token| token.matching == MatchKind::Signed)
+            .filter(|token| token.secret == signing_key.id())
+            .filter(|_| signing_key.verify(label, signature))
+    }
+
+    /// The token string to hand out for a signed token.
+    pub fn sign(&self, token_label: &str) -> Result<String> {
+        let signing_key = self
+            .options
+            .signing_key
+            .as_ref()
+            .ok_or_else(|| TokenStoreError::Key("No signing key configured".to_string()))?;
+        Ok(format!("{}.{}", token_label, signing_key.sign(token_label)))
+    }
+
+    /// Finds the label a token string belongs to. Exact and hashed secrets
+    /// are looked up first, only then are the patterns tried one by one.
+    fn label_for(&self, token_string: &str) -> Result<Option<&String>> {
+        let token_lookup = self
             .token_lookup
             .as_ref()
-            .ok_or_else(|| anyhow!("Token store not loaded!"))?;
-        Ok(token_store.get(token_string).is_some())
+            .ok_or(TokenStoreError::NotLoaded)?;
+        if let Some(label) = token_lookup.get(token_string) {
+            return Ok(Some(label));
+        }
+        if !self.hashed_lookup.is_empty() {
+            if let Some(label) = self.hashed_lookup.get(&hash_secret(token_string)) {
+                return Ok(Some(label));
+            }
+        }
+        Ok(self
+            .patterns
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(token_string))
+            .map(|(_, label)| label))
+    }
+
+    /// The token a token string belongs to, provided it would be let in
+    /// right now. Unlike `admit`, no use is counted against it.
+    pub fn active_token(&self, token_string: &str) -> Result<Option<&Token>> {
+        let Some(token) = self.authenticate(token_string)? else {
+            return Ok(None);
+        };
+        let expired = token.is_expired(self.now().unwrap_or_default());
+        let exhausted = self.usage().remaining.get(&token.label) == Some(&0);
+        Ok((!expired && !exhausted).then_some(token))
+    }
+
+    /// Checks a token string presented by a client, counting it against the
+    /// token's remaining uses if it has a limit.
+    pub fn use_token(&self, token_string: &str) -> Result<TokenUse> {
+        match self.authenticate(token_string)? {
+            Some(token) => self.admit(token),
+            None => Ok(TokenUse::Unknown),
+        }
+    }
+
+    /// Decides whether an authenticated token may be used right now, and if
+    /// so counts the use against it.
+    pub fn admit(&self, token: &Token) -> Result<TokenUse> {
+        let label = &token.label;
+        let now = self.now();
+        if token.is_expired(now.unwrap_or_default()) {
+            return Ok(TokenUse::Expired(label.clone()));
+        }
+        let mut usage = self.usage();
+        let counted = usage.remaining.contains_key(label);
+        let flush_interval = match usage.remaining.get(label).copied() {
+            Some(0) => return Ok(TokenUse::Exhausted(label.clone())),
+            Some(remaining) => {
+                usage.remaining.insert(label.clone(), remaining - 1);
+                *usage.unflushed.entry(label.clone()).or_default() += 1;
+                // the last use is written straight away, it must not be handed out twice
+                match remaining - 1 {
+                    0 => Duration::ZERO,
+                    _ => USAGE_FLUSH_INTERVAL,
+                }
+            }
+            None => LAST_USED_FLUSH_INTERVAL,
+        };
+        if let Some(now) = now {
+            usage.last_used.insert(label.clone(), now);
+        }
+        let flush = flush_interval.is_zero()
+            || usage
+                .last_flushed
+                .is_none_or(|last_flushed| last_flushed.elapsed() >= flush_interval);
+        // a fetched store cannot be written back, so its uses are only
+        // counted in memory
+        if flush && self.remote.is_none() {
+            usage.last_flushed = Some(Instant::now());
+            drop(usage);
+            match self.persist_usage_with_retry() {
+                // no write will ever get through, so uses are only counted
+                // in memory, as for a fetched store
+                Err(e @ (TokenStoreError::NotAStore(_) | TokenStoreError::ReadOnly(_))) => {
+                    self.warn_unwritable(&e)
+                }
+                // losing the time of a use is no reason to turn the request away
+                Err(e) if counted => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(TokenUse::Allowed(label.clone()))
+    }
+
+    /// Says once that uses will not make it into the file.
+    fn warn_unwritable(&self, e: &TokenStoreError) {
+        if !std::mem::replace(&mut self.usage().unwritable, true) {
+            crate::log!("WARNING: {}, uses are only counted in memory", e);
+        }
+    }
+
+    /// When the token last let a request in, including uses not yet
+    /// written to the file.
+    pub fn last_used_at(&self, token_label: &str) -> Option<u64> {
+        self.usage().last_used.get(token_label).copied()
+    }
+
+    fn usage(&self) -> MutexGuard<'_, Usage> {
+        match self.usage.lock() {
+            Ok(usage) => usage,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn usage_mut(&mut self) -> &mut Usage {
+        match self.usage.get_mut() {
+            Ok(usage) => usage,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Writes the uses left as of the last flush into the tokens held,
+    /// which is what the file has for them. A flush only updates the file,
+    /// so the next rebuild would otherwise count down from what the tokens
+    /// said before it and hand the flushed uses back.
+    fn catch_up_usage(&mut self) {
+        let usage = match self.usage.get_mut() {
+            Ok(usage) => usage,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let Some(token_map) = self.tokens.as_mut() else {
+            return;
+        };
+        for token in token_map.values_mut() {
+            if let Some(remaining) = usage.remaining.get(&token.label) {
+                let counted = usage
+                    .unflushed
+                    .get(&token.label)
+                    .copied()
+                    .unwrap_or_default();
+                token.remaining_uses = Some(remaining + counted);
+            }
+        }
     }
 
     fn rebuild_token_lookup(&mut self) -> Result<()> {
+        // uses seen since the last flush are newer than the file
+        let seen = std::mem::take(&mut self.usage_mut().last_used);
+        let mut unflushed = std::mem::take(&mut self.usage_mut().unflushed);
         let Some(token_map) = self.tokens.as_mut() else {
-            return Err(anyhow!("Token store not yet loaded"));
+            return Err(TokenStoreError::NotLoaded);
         };
-        let mut token_lookup = HashSet::new();
-        token_map.values().for_each(|token| {
-            token_lookup.insert(token.1.clone());
-        });
+        let mut token_lookup = HashMap::new();
+        let mut hashed_lookup = HashMap::new();
+        let mut patterns = Vec::new();
+        let mut remaining = HashMap::new();
+        let mut last_used = HashMap::new();
+        for token in token_map.values() {
+            match token
+                .pattern()
+                .map_err(|e| TokenStoreError::Parse(e.to_string()))?
+            {
+                Some(pattern) => patterns.push((pattern, token.label.clone())),
+                // signed tokens are checked by recomputing the signature, never looked up
+                None if token.matching == MatchKind::Signed => {}
+                // kept apart, a hash presented as a token string must not match
+                None if token.matching == MatchKind::Hashed => {
+                    hashed_lookup.insert(token.secret.clone(), token.label.clone());
+                }
+                None => {
+                    token_lookup.insert(token.secret.clone(), token.label.clone());
+                }
+            }
+            if let Some(uses) = token.remaining_uses {
+                let counted = unflushed.get(&token.label).copied().unwrap_or_default();
+                remaining.insert(token.label.clone(), uses.saturating_sub(counted));
+            }
+            if let Some(used) = token.last_used_at.max(seen.get(&token.label).copied()) {
+                last_used.insert(token.label.clone(), used);
+            }
+        }
+        // the scan order has to be stable for overlapping patterns to behave
+        patterns.sort_by(|a, b| a.1.cmp(&b.1));
         self.token_lookup = Some(token_lookup);
+        self.hashed_lookup = hashed_lookup;
+        self.patterns = patterns;
+        unflushed.retain(|label, _| token_map.contains_key(label));
+        let usage = self.usage_mut();
+        usage.remaining = remaining;
+        usage.last_used = last_used;
+        usage.unflushed = unflushed;
         Ok(())
     }
 
-    pub fn create(&mut self, token_label: &str) -> Result<Token> {
-        let Some(token_map) = self.tokens.as_mut() else {
-            return Err(anyhow!("Token store not yet loaded"));
+    /// Adds a token with a generated secret, or a signed token when the
+    /// store has a signing key.
+    pub fn create(&mut self, token_label: &str, options: TokenOptions) -> Result<Token> {
+        if self.options.signing_key.is_none() {
+            let secret = self.options.secret_generator.generate();
+            return self.create_with_secret(token_label, &secret, options);
+        }
+        let token = self.generate(token_label, options)?;
+        self.insert_token(token)
+    }
+
+    /// Adds a token as `create` does, expiring `ttl` from now.
+    pub fn create_with_ttl(&mut self, token_label: &str, ttl: Duration) -> Result<Token> {
+        let now = self.now().unwrap_or_default();
+        let options = TokenOptions {
+            expires_at: Some(now.saturating_add(ttl.as_secs())),
+            ..TokenOptions::default()
         };
-        if token_map.contains_key(token_label) {
-            return Err(anyhow!("Labels must be unique!"));
+        self.create(token_label, options)
+    }
+
+    /// Adds a token for each label as `create` does, but writes the store
+    /// once at the end. Every label gets an outcome of its own, so that a
+    /// taken or invalid label leaves the rest of the batch to go ahead.
+    pub fn create_batch(
+        &mut self,
+        token_labels: &[String],
+        options: &TokenOptions,
+    ) -> Result<Vec<Result<Token>>> {
+        if self.tokens.is_none() {
+            return Err(TokenStoreError::NotLoaded);
         }
-        let new_token = Token(token_label.to_string(), Uuid::new_v4().to_string());
-        token_map.insert(token_label.to_string(), new_token.clone());
-        self.rebuild_token_lookup()?;
-        self.persist_to_file()?;
+        let snapshot = self.snapshot();
+        let created: Vec<Result<Token>> = token_labels
+            .iter()
+            .map(|token_label| {
+                let token = self.generate(token_label, options.clone())?;
+                self.insert_uncommitted(token)
+            })
+            .collect();
+        if created.iter().any(Result::is_ok) {
+            self.commit(snapshot)?;
+        }
+        Ok(created)
+    }
+
+    /// A new token with a generated secret, or a signed one when the store
+    /// has a signing key. Generated secrets are taken to be fresh, so they
+    /// are not checked against those already held.
+    pub(crate) fn generate(&self, token_label: &str, options: TokenOptions) -> Result<Token> {
+        let (secret, matching) = match self.options.signing_key.as_ref() {
+            Some(signing_key) => (signing_key.id(), MatchKind::Signed),
+            None => (self.options.secret_generator.generate(), options.matching),
+        };
+        self.check_lengths(token_label, &secret)?;
+        let token = Token::new(token_label, &secret)?;
+        self.check_label(token_label)?;
+        Ok(Token {
+            matching,
+            ..with_options(token, options)
+        })
+    }
+
+    /// Adds a token with a secret chosen by the caller rather than generated.
+    pub fn create_with_secret(
+        &mut self,
+        token_label: &str,
+        secret: &str,
+        options: TokenOptions,
+    ) -> Result<Token> {
+        let token = Token::new(token_label, secret)?;
+        self.check_label(token_label)?;
+        self.check_secret(secret)?;
+        self.check_lengths(token_label, secret)?;
+        // an expired token still holds its secret, a renewal may bring it back
+        if self.authenticate(secret)?.is_some() {
+            return Err(TokenStoreError::DuplicateSecret);
+        }
+        self.insert_token(with_options(token, options))
+    }
+
+    fn insert_token(&mut self, new_token: Token) -> Result<Token> {
+        let snapshot = self.snapshot();
+        let new_token = self.insert_uncommitted(new_token)?;
+        self.commit(snapshot)?;
         Ok(new_token)
     }
 
+    /// With `hash_secrets`, the token handed back is the only place the
+    /// plaintext secret is still to be found.
+    fn insert_uncommitted(&mut self, new_token: Token) -> Result<Token> {
+        let new_token = Token {
+            created_at: self.now(),
+            ..new_token
+        };
+        let stored = self.conceal(new_token.clone());
+        let Some(token_map) = self.tokens.as_mut() else {
+            return Err(TokenStoreError::NotLoaded);
+        };
+        if token_map.contains_key(&stored.label) {
+            return Err(TokenStoreError::DuplicateLabel(stored.label));
+        }
+        token_map.insert(stored.label.clone(), stored.clone());
+        Ok(Token {
+            secret: new_token.secret,
+            ..stored
+        })
+    }
+
+    /// The token as it is kept, given one still holding its plaintext
+    /// secret. A hashed token, or with `hash_secrets` an exact one, keeps
+    /// the hash of the secret instead.
+    pub fn conceal(&self, token: Token) -> Token {
+        let hashed = match token.matching {
+            MatchKind::Hashed => true,
+            MatchKind::Exact => self.options.hash_secrets,
+            MatchKind::Regex | MatchKind::Signed => false,
+        };
+        match hashed {
+            true => Token {
+                secret: hash_secret(&token.secret),
+                matching: MatchKind::Hashed,
+                ..token
+            },
+            false => token,
+        }
+    }
+
+    /// Gives a token a freshly generated secret, keeping its label and all
+    /// else about it, uses left included. The old secret stops working at
+    /// once.
+    pub fn rotate(&mut self, token_label: &str) -> Result<Token> {
+        let snapshot = self.snapshot();
+        let Some(token) = self.get(token_label)?.cloned() else {
+            return Err(TokenStoreError::NotFound(token_label.to_string()));
+        };
+        if matches!(token.matching, MatchKind::Regex | MatchKind::Signed) {
+            return Err(TokenStoreError::NotRotatable(token.label));
+        }
+        let secret = self.options.secret_generator.generate();
+        self.check_lengths(token_label, &secret)?;
+        // uses counted since the last flush are taken off by the rebuild,
+        // and only then written out with the rest
+        let rotated = Token { secret, ..token };
+        let stored = self.conceal(rotated.clone());
+        let Some(token_map) = self.tokens.as_mut() else {
+            return Err(TokenStoreError::NotLoaded);
+        };
+        token_map.insert(stored.label.clone(), stored);
+        self.commit(snapshot)?;
+        let remaining_uses = self.usage().remaining.get(token_label).copied();
+        Ok(Token {
+            remaining_uses: remaining_uses.or(rotated.remaining_uses),
+            ..rotated
+        })
+    }
+
     pub fn rescind(&mut self, token_label: &str) -> Result<()> {
+        let snapshot = self.snapshot();
         let Some(token_map) = self.tokens.as_mut() else {
-            return Err(anyhow!("Token store not yet loaded"));
+            return Err(TokenStoreError::NotLoaded);
         };
         if !token_map.contains_key(token_label) {
-            return Err(anyhow!("No token associated with key!"));
+            return Err(TokenStoreError::NotFound(token_label.to_string()));
         }
         token_map.remove(token_label);
-        self.rebuild_token_lookup()?;
-        self.persist_to_file()?;
-        Ok(())
+        self.commit(snapshot)
+    }
+
+    /// The label of the token a secret was issued with, for when a secret
+    /// leaks without its label. A pattern is no one secret's token, and
+    /// rescinding it would revoke everything else it matches too.
+    pub fn owner_of(&self, token_string: &str) -> Result<String> {
+        match self.authenticate(token_string)? {
+            Some(token) if token.matching == MatchKind::Regex => {
+                Err(TokenStoreError::PatternOnly(token.label.clone()))
+            }
+            Some(token) => Ok(token.label.clone()),
+            None => Err(TokenStoreError::UnknownSecret),
+        }
+    }
+
+    /// Rescinds the token a secret was issued with, see `owner_of`,
+    /// returning its label.
+    pub fn rescind_by_token(&mut self, token_string: &str) -> Result<String> {
+        let label = self.owner_of(token_string)?;
+        self.rescind(&label)?;
+        Ok(label)
+    }
+
+    /// Rescinds a token, leaving a tombstone recording when it was revoked.
+    pub fn rescind_with_tombstone(&mut self, token_label: &str) -> Result<()> {
+        let snapshot = self.snapshot();
+        let Some(token_map) = self.tokens.as_mut() else {
+            return Err(TokenStoreError::NotLoaded);
+        };
+        if token_map.remove(token_label).is_none() {
+            return Err(TokenStoreError::NotFound(token_label.to_string()));
+        }
+        self.tombstones.push(Tombstone {
+            label: token_label.to_string(),
+            revoked_at: self.now(),
+        });
+        self.commit(snapshot)
+    }
+
+    /// Drops tombstones revoked before the cutoff, in seconds since the
+    /// epoch, returning how many went. Those without a revocation time are
+    /// kept, there is no telling how old they are.
+    pub fn compact(&mut self, revoked_before: u64) -> Result<usize> {
+        let snapshot = self.snapshot();
+        let before = self.tombstones.len();
+        self.tombstones.retain(|tombstone| {
+            tombstone
+                .revoked_at
+                .is_none_or(|revoked_at| revoked_at >= revoked_before)
+        });
+        let compacted = before - self.tombstones.len();
+        if compacted > 0 {
+            self.commit(snapshot)?;
+        }
+        Ok(compacted)
+    }
+
+    /// Drops the tokens past their expiry, returning how many went. Left
+    /// alone they stay on disk, where a renewal can bring them back, but
+    /// are never let in.
+    pub fn prune_expired(&mut self) -> Result<usize> {
+        let snapshot = self.snapshot();
+        let now = self.now().unwrap_or_default();
+        let Some(token_map) = self.tokens.as_mut() else {
+            return Err(TokenStoreError::NotLoaded);
+        };
+        let before = token_map.len();
+        token_map.retain(|_, token| !token.is_expired(now));
+        let pruned = before - token_map.len();
+        if pruned > 0 {
+            self.commit(snapshot)?;
+        }
+        Ok(pruned)
+    }
+
+    /// Swaps out every token at once, e.g. for a repaired set. The labels
+    /// must be unique, tombstones are left as they are.
+    pub fn replace_tokens(&mut self, tokens: Vec<Token>) -> Result<()> {
+        let snapshot = self.snapshot();
+        let mut token_map = HashMap::new();
+        for token in tokens {
+            if token_map.contains_key(&token.label) {
+                return Err(TokenStoreError::DuplicateLabel(token.label));
+            }
+            token_map.insert(token.label.clone(), token);
+        }
+        self.tokens = Some(token_map);
+        self.commit(snapshot)
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn format(&self) -> StoreFormat {
+        self.options.format
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.options.key.is_some()
+    }
+
+    /// Seconds since the epoch according to the store's clock.
+    pub fn now(&self) -> Option<u64> {
+        self.options.clock.now()
+    }
+
+    pub fn is_signing(&self) -> bool {
+        self.options.signing_key.is_some()
+    }
+
+    /// Whether the store has been read at least once.
+    pub fn is_loaded(&self) -> bool {
+        self.tokens.is_some()
+    }
+
+    /// Whether the store is fetched over HTTP, and so read only.
+    pub fn is_remote(&self) -> bool {
+        self.remote.is_some()
+    }
+
+    /// True when there are no tokens that could be let in, tombstones aside.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.as_ref().is_none_or(HashMap::is_empty)
+    }
+
+    pub fn tombstones(&self) -> impl Iterator<Item = &Tombstone> {
+        self.tombstones.iter()
+    }
+
+    pub fn get(&self, token_label: &str) -> Result<Option<&Token>> {
+        self.tokens
+            .as_ref()
+            .ok_or(TokenStoreError::NotLoaded)
+            .map(|token_map| token_map.get(token_label))
     }
 
     pub fn iter(&self) -> Result<impl Iterator<Item = &Token>> {
         self.tokens
             .as_ref()
-            .ok_or_else(|| anyhow!("Token store not yet loaded"))
+            .ok_or(TokenStoreError::NotLoaded)
             .map(|token_map| token_map.values())
     }
 }
+
+/// Seconds since the epoch, if the clock is not set before it.
+pub fn epoch_now() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .ok()
+}
+
+fn parse_entries(contents: &str) -> Result<Vec<Entry>> {
+    contents
+        .lines()
+        .map(|line| {
+            Entry::parse(line).map_err(|_| {
+                TokenStoreError::Parse(format!("Failed to parse token from line: {}", line))
+            })
+        })
+        .collect()
+}
+
+/// Where a new store is written before taking the place of the old one,
+/// `.<file name>.<pid>.partial` next to it. Kept in the same directory so
+/// that the rename stays on one filesystem, and named after the process so
+/// that writers in other processes do not clobber it.
+fn partial_path(target: &Path) -> PathBuf {
+    let file_name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{}.{}.partial", file_name, process::id()))
+}
+
+/// Writes the new store in full and syncs it, with the permissions of the
+/// store it is about to replace.
+fn write_partial(partial: &Path, target: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(partial)?;
+    if let Ok(metadata) = fs::metadata(target) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Takes the advisory lock on the directory of the store, held until the
+/// file returned is dropped. Other processes writing the store take it
+/// too, renaming the new store into place leaves a lock on the file itself
+/// behind on the old one.
+#[cfg(unix)]
+fn lock_dir(target: &Path) -> Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    let dir_path = match target.parent() {
+        Some(dir_path) if !dir_path.as_os_str().is_empty() => dir_path,
+        _ => Path::new("."),
+    };
+    let lock_error = || format!("Unable to lock store directory {}", dir_path.display());
+    let dir = File::open(dir_path).map_err(TokenStoreError::io(lock_error()))?;
+    // SAFETY: flock only acts on the descriptor, which stays open for as
+    // long as the file returned is held
+    if unsafe { libc::flock(dir.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(TokenStoreError::io(lock_error())(
+            std::io::Error::last_os_error(),
+        ));
+    }
+    Ok(dir)
+}
+
+/// Without advisory locks, only writers within this process take turns.
+#[cfg(not(unix))]
+fn lock_dir(_target: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn with_options(token: Token, options: TokenOptions) -> Token {
+    Token {
+        annotations: options.annotations,
+        remaining_uses: options.max_uses,
+        scopes: options.scopes,
+        expires_at: options.expires_at,
+        matching: options.matching,
+        ..token
+    }
+}
+
+/// Remaining uses and last uses are left out, the file only catches up with
+/// them now and then so they are bound to differ.
+fn same_apart_from_uses(a: &Token, b: &Token) -> bool {
+    let strip = |token: &Token| Token {
+        remaining_uses: None,
+        last_used_at: None,
+        ..token.clone()
+    };
+    strip(a) == strip(b)
+}
@@ -0,0 +1,60 @@
+use std::{
+    io,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads taking jobs in the order they were given.
+/// Dropping the pool waits for every job already given to finish.
+pub struct WorkerPool {
+    sender: Option<mpsc::SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts `size` workers, at least one. Once every worker is busy and
+    /// as many jobs again are waiting, `execute` blocks until one is taken.
+    pub fn new(size: usize) -> io::Result<Self> {
+        let size = size.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Job>(size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|index| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("mellon-worker-{}", index))
+                    .spawn(move || loop {
+                        // the lock is only held while waiting for a job, not
+                        // while working on one
+                        let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => return,
+                        }
+                    })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(WorkerPool {
+            sender: Some(sender),
+            workers,
+        })
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if let Some(sender) = self.sender.as_ref() {
+            // the workers only hang up once the sender is dropped
+            let _ = sender.send(Box::new(job));
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
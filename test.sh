@@ -1,8 +1,26 @@
 cargo build
 mellon="./target/debug/mellon"
 
+# serving an empty store should be refused, unless the store is already in use
+if ! grep -qv '^# mellon token store$' /tmp/mellon/tokens 2>/dev/null; then
+	if $mellon serve localhost:8091 > /dev/null; then
+		echo "Error with empty store: server started"
+	else
+		echo "Success with empty store: server refused to start"
+	fi
+fi
+
 token=$($mellon token add testing_token)
 echo "Got temporary token: $token"
+scoped_token=$($mellon token add testing_scoped_token --scope "GET /api")
+$mellon token add testing_pattern_token --match regex --secret 'worker-[0-9]+' > /dev/null
+
+# whoami names the label a token belongs to
+if [[ "$(echo "$token" | $mellon token whoami)" == "testing_token" ]]; then
+	echo "Success with whoami: token belongs to testing_token"
+else
+	echo "Error with whoami: token not recognised"
+fi
 
 nohup $mellon serve &
 SERVER_PID=$!
@@ -21,8 +39,162 @@ for verb in "${verbs[@]}"; do
 	fi
 done
 
-# remove the token
-$mellon token rescind testing_token
+# an oversized request line should be refused before the headers are read
+long_path=$(head -c 9000 /dev/zero | tr '\0' 'a')
+response=$(curl -o /dev/null -s -w "%{http_code}" "localhost:8090/$long_path" -H "Authorization: Bearer $token")
+if [[ "$response" == "414" ]]; then
+	echo "Success with oversized path: HTTP response is 414"
+else
+	echo "Error with oversized path: HTTP response is not 414, it is $response"
+fi
+
+# a scoped token is only let through for matching method and path, behind a
+# proxy those of the original request
+scope_checks=(
+	"200 GET /api/users"
+	"200 GET /auth -H X-Original-Method:GET -H X-Original-URI:/api?page=2"
+	"403 POST /api"
+	"403 GET /apiary"
+	"403 GET /admin"
+	"403 GET /auth -H X-Original-Method:DELETE -H X-Original-URI:/api"
+)
+for check in "${scope_checks[@]}"; do
+	read -r expected verb path extra <<< "$check"
+	response=$(curl -o /dev/null -s -w "%{http_code}" -X "$verb" "localhost:8090$path" $extra -H "Authorization: Bearer $scoped_token")
+	if [[ "$response" == "$expected" ]]; then
+		echo "Success with scoped $verb $path $extra: HTTP response is $expected"
+	else
+		echo "Error with scoped $verb $path $extra: HTTP response is not $expected, it is $response"
+	fi
+done
+
+# a pattern entry lets in every token string it matches in full
+pattern_checks=(
+	"200 worker-17"
+	"401 worker-x"
+	"401 xworker-17"
+	"401 worker-17x"
+)
+for check in "${pattern_checks[@]}"; do
+	read -r expected presented <<< "$check"
+	response=$(curl -o /dev/null -s -w "%{http_code}" localhost:8090/auth -H "Authorization: Bearer $presented")
+	if [[ "$response" == "$expected" ]]; then
+		echo "Success with pattern token $presented: HTTP response is $expected"
+	else
+		echo "Error with pattern token $presented: HTTP response is not $expected, it is $response"
+	fi
+done
+
+# a one shot server should exit with the outcome of the request it served
+$mellon serve localhost:8091 --once > /dev/null &
+ONCE_PID=$!
+sleep 1
+curl -o /dev/null -s localhost:8091/auth
+wait $ONCE_PID
+status=$?
+if [[ "$status" == "1" ]]; then
+	echo "Success with --once: exit status is 1"
+else
+	echo "Error with --once: exit status is not 1, it is $status"
+fi
+
+# the last of --fail-open and --fail-closed wins
+fail_log=$(mktemp)
+$mellon serve localhost:8091 --once --fail-open --fail-closed > "$fail_log" 2>&1 &
+ONCE_PID=$!
+sleep 1
+curl -o /dev/null -s localhost:8091/auth
+wait $ONCE_PID
+if grep -q "failing open" "$fail_log"; then
+	echo "Error with --fail-closed: still failing open"
+else
+	echo "Success with --fail-closed: overrides --fail-open"
+fi
+rm -f "$fail_log"
+
+# the access log names the token let in, in Common Log Format
+access_log=$(mktemp)
+$mellon serve localhost:8091 --once --log-format clf --log-file "$access_log" > /dev/null &
+ONCE_PID=$!
+sleep 1
+curl -o /dev/null -s localhost:8091/auth -H "Authorization: Bearer $token"
+wait $ONCE_PID
+if grep -q '^127.0.0.1 - testing_token \[.*\] "GET /auth HTTP/1.1" 200 -$' "$access_log"; then
+	echo "Success with --log-format clf: request logged"
+else
+	echo "Error with --log-format clf: request not logged as expected"
+fi
+rm -f "$access_log"
+
+# a polling server should pick up tokens added after it started
+$mellon serve localhost:8091 --reload-interval 1 > /dev/null 2>&1 &
+POLLING_PID=$!
+sleep 1
+late_token=$($mellon token add testing_late_token)
+sleep 2
+response=$(curl -o /dev/null -s -w "%{http_code}" localhost:8091/auth -H "Authorization: Bearer $late_token")
+if [[ "$response" == "200" ]]; then
+	echo "Success with --reload-interval: HTTP response is 200"
+else
+	echo "Error with --reload-interval: HTTP response is not 200, it is $response"
+fi
+kill $POLLING_PID
+
+# the admin token alone does not turn on /admin/reload, --admin-reload does
+for flag in "" --admin-reload; do
+	MELLON_ADMIN_TOKEN=admin-secret $mellon serve localhost:8091 --once $flag > /dev/null 2>&1 &
+	ONCE_PID=$!
+	sleep 1
+	response=$(curl -o /dev/null -s -w "%{http_code}" -X POST localhost:8091/admin/reload -H "Authorization: Bearer admin-secret")
+	wait $ONCE_PID
+	expected=$([[ -n "$flag" ]] && echo 200 || echo 401)
+	if [[ "$response" == "$expected" ]]; then
+		echo "Success with MELLON_ADMIN_TOKEN${flag:+ and $flag}: HTTP response is $expected"
+	else
+		echo "Error with MELLON_ADMIN_TOKEN${flag:+ and $flag}: HTTP response is not $expected, it is $response"
+	fi
+done
+
+# remove the token, the first one by its secret alone
+if echo "$token" | $mellon token rescind --by-secret | grep -q "label testing_token "; then
+	echo "Success with rescind --by-secret: removed testing_token"
+else
+	echo "Error with rescind --by-secret: testing_token not removed"
+fi
+$mellon token rescind testing_scoped_token
+$mellon token rescind testing_pattern_token
+rotated_token=$($mellon token rotate testing_late_token)
+if [[ "$rotated_token" != "$late_token" ]] && echo "$rotated_token" | $mellon token whoami | grep -q "^testing_late_token$"; then
+	echo "Success with token rotate: testing_late_token has a new secret"
+else
+	echo "Error with token rotate: testing_late_token kept its secret"
+fi
+late_token=$rotated_token
+
+# secrets are masked by character, however many bytes each one takes
+$mellon token add testing_unicode_token --secret 'pässwörd-ünïcödé' > /dev/null
+if $mellon token list --no-truncate | grep -q '\*\*\*\*\*\*\*\*\*\*\*\*cödé'; then
+	echo "Success with token list: non-ASCII secret masked"
+else
+	echo "Error with token list: non-ASCII secret not masked as expected"
+fi
+$mellon token rescind testing_unicode_token
+
+# tombstones give their revocation time like any other time in the list
+tomb_store=$(mktemp)
+$mellon token add testing_tombstone --store "$tomb_store" > /dev/null
+$mellon token rescind testing_tombstone --keep-tombstones --store "$tomb_store" > /dev/null
+if $mellon token list --include-revoked --store "$tomb_store" | grep -qE 'revoked at [0-9]{4}-[0-9]{2}-[0-9]{2}T'; then
+	echo "Success with token list: tombstone revocation time is RFC 3339"
+else
+	echo "Error with token list: tombstone revocation time is not RFC 3339"
+fi
+rm -f "$tomb_store"
+if $mellon token rescind --by-token "$late_token" | grep -q "label testing_late_token "; then
+	echo "Success with rescind --by-token: removed testing_late_token"
+else
+	echo "Error with rescind --by-token: testing_late_token not removed"
+fi
 
 # stop the server
 kill $SERVER_PID
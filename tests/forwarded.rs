@@ -0,0 +1,73 @@
+use std::net::IpAddr;
+
+use mellon::forwarded::{client_ip, Cidr};
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+#[test]
+fn believes_forwarded_for_only_from_trusted_peers() {
+    let trusted: Vec<Cidr> = ["10.0.0.0/8", "192.168.1.5"]
+        .iter()
+        .map(|cidr| cidr.parse().unwrap())
+        .collect();
+
+    // the load balancer is trusted, so the client it names is logged
+    assert_eq!(
+        client_ip(ip("10.1.2.3"), Some("203.0.113.7"), &trusted),
+        ip("203.0.113.7")
+    );
+    // a client connecting directly cannot pass itself off as someone else
+    assert_eq!(
+        client_ip(ip("198.51.100.1"), Some("203.0.113.7"), &trusted),
+        ip("198.51.100.1")
+    );
+    assert_eq!(
+        client_ip(ip("192.168.1.6"), Some("203.0.113.7"), &trusted),
+        ip("192.168.1.6")
+    );
+    assert_eq!(client_ip(ip("10.1.2.3"), None, &trusted), ip("10.1.2.3"));
+}
+
+#[test]
+fn skips_trusted_hops_but_not_what_the_client_wrote() {
+    let trusted = vec!["10.0.0.0/8".parse::<Cidr>().unwrap()];
+
+    // the client prepended an address of its choosing ahead of its own
+    assert_eq!(
+        client_ip(
+            ip("10.0.0.1"),
+            Some("1.1.1.1, 203.0.113.7, 10.0.0.2"),
+            &trusted
+        ),
+        ip("203.0.113.7")
+    );
+    // with nothing but proxies in the chain, the furthest one is the client
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), Some("10.0.0.3, 10.0.0.2"), &trusted),
+        ip("10.0.0.3")
+    );
+    assert_eq!(
+        client_ip(ip("10.0.0.1"), Some("garbage, 10.0.0.2"), &trusted),
+        ip("10.0.0.2")
+    );
+    assert_eq!(
+        client_ip(ip("::ffff:10.0.0.1"), Some("[2001:db8::1]:443"), &trusted),
+        ip("2001:db8::1")
+    );
+}
+
+#[test]
+fn parses_ranges() {
+    let cidr = "2001:db8::/32".parse::<Cidr>().unwrap();
+    assert!(cidr.contains(ip("2001:db8:ffff::1")));
+    assert!(!cidr.contains(ip("2001:db9::1")));
+    assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+    assert_eq!(
+        "192.168.1.5".parse::<Cidr>().unwrap().to_string(),
+        "192.168.1.5/32"
+    );
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("not-an-address/8".parse::<Cidr>().is_err());
+}
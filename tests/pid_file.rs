@@ -0,0 +1,29 @@
+use std::{fs, process};
+
+use mellon::pid_file::PidFile;
+
+#[test]
+fn replaces_a_stale_pid_file_but_not_a_live_one() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mellon.pid");
+
+    // a child that has been waited on has an id nothing else holds for now
+    let mut child = process::Command::new("true").spawn().unwrap();
+    child.wait().unwrap();
+    fs::write(&path, format!("{}\n", child.id())).unwrap();
+    let pid_file = PidFile::create(path.clone(), false).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        format!("{}\n", process::id())
+    );
+
+    // our own process is certainly running
+    assert!(PidFile::create(path.clone(), false).is_err());
+    drop(pid_file);
+    assert!(!path.exists());
+
+    fs::write(&path, format!("{}\n", process::id())).unwrap();
+    let forced = PidFile::create(path.clone(), true).unwrap();
+    drop(forced);
+    assert!(!path.exists());
+}
@@ -0,0 +1,1011 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tempfile::TempDir;
+
+use mellon::response_template::ResponseTemplate;
+use mellon::{
+    logging, BasicSecret, Clock, LogFormat, MellonClient, MellonServer, SequenceGenerator,
+    ServerOptions, StoreOptions, SystemClock, Token, TokenOptions, TokenStore,
+};
+
+/// Serves a store holding a single token on port 0 from another thread,
+/// returning the port bound and the token. The store lives as long as the
+/// directory returned.
+fn start_server(options: ServerOptions) -> (TempDir, u16, Token) {
+    start_server_with_store(StoreOptions::default(), options)
+}
+
+fn start_server_with_store(
+    store_options: StoreOptions,
+    options: ServerOptions,
+) -> (TempDir, u16, Token) {
+    start_server_with_token(store_options, TokenOptions::default(), options)
+}
+
+fn start_server_with_token(
+    store_options: StoreOptions,
+    token_options: TokenOptions,
+    options: ServerOptions,
+) -> (TempDir, u16, Token) {
+    let dir = tempfile::tempdir().unwrap();
+    let store_path = dir.path().join("tokens").to_string_lossy().into_owned();
+
+    let mut token_store = TokenStore::new(store_path, store_options).unwrap();
+    let token = token_store.create("service", token_options).unwrap();
+    let port = serve_store(&dir, token_store, options);
+    (dir, port, token)
+}
+
+/// Serves the store on port 0 from another thread, returning the port once
+/// the server has written it into `dir`.
+fn serve_store(dir: &TempDir, token_store: TokenStore, options: ServerOptions) -> u16 {
+    let port_file = dir.path().join("port");
+    let options = ServerOptions {
+        port_file: Some(port_file.clone()),
+        ..options
+    };
+    thread::spawn(move || MellonServer::serve("127.0.0.1:0".to_string(), token_store, options));
+
+    let mut port = None;
+    for _ in 0..100 {
+        if let Ok(contents) = fs::read_to_string(&port_file) {
+            port = contents.trim().parse::<u16>().ok();
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    port.expect("server never wrote its port")
+}
+
+#[test]
+fn reports_the_port_bound_for_port_zero() {
+    let (_dir, port, token) = start_server(ServerOptions::default());
+    assert_ne!(port, 0);
+
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let response = client.get("/auth", Some(&token.secret)).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("connection"), Some("close"));
+    assert_eq!(client.get("/auth", None).unwrap().status, 401);
+}
+
+#[test]
+fn answers_promptly_when_the_headers_end_at_eof() {
+    let (_dir, port, token) = start_server(ServerOptions::default());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    // no blank line, nor even a line ending, before the client stops sending
+    write!(
+        stream,
+        "GET /auth HTTP/1.1\nAuthorization: Bearer {}",
+        token.secret
+    )
+    .unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let started = Instant::now();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn gives_up_on_a_client_gone_quiet_after_the_read_timeout() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        read_timeout: Duration::from_millis(200),
+        ..ServerOptions::default()
+    });
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        stream,
+        "GET /auth HTTP/1.1\r\nAuthorization: Bearer {}\r\n",
+        token.secret
+    )
+    .unwrap();
+    // the blank line ending the headers never comes
+    let started = Instant::now();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 408 REQUEST TIMEOUT"),
+        "{}",
+        response
+    );
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn gives_up_on_a_slow_drip_at_the_request_deadline() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        read_timeout: Duration::from_secs(5),
+        max_request_time: Some(Duration::from_millis(600)),
+        ..ServerOptions::default()
+    });
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        stream,
+        "GET /auth HTTP/1.1\r\nAuthorization: Bearer {}\r\n",
+        token.secret
+    )
+    .unwrap();
+    // a header every 100ms stays well within the read timeout
+    let mut drip = stream.try_clone().unwrap();
+    let started = Instant::now();
+    thread::spawn(move || {
+        while started.elapsed() < Duration::from_secs(3) {
+            if drip.write_all(b"X-Drip: 1\r\n").is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(
+        response.starts_with("HTTP/1.1 408 REQUEST TIMEOUT"),
+        "{}",
+        response
+    );
+    assert!(started.elapsed() >= Duration::from_millis(600));
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn serves_connections_side_by_side() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        workers: 4,
+        ..ServerOptions::default()
+    });
+
+    // clients that connect and then say nothing tie up a worker each,
+    // until their read times out
+    let mut idle: Vec<TcpStream> = (0..3)
+        .map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap())
+        .collect();
+    let client =
+        MellonClient::new(format!("127.0.0.1:{}", port)).with_timeout(Duration::from_secs(5));
+    let started = Instant::now();
+    assert_eq!(
+        client.get("/auth", Some(&token.secret)).unwrap().status,
+        200
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    for stream in idle.iter_mut() {
+        write!(
+            stream,
+            "GET /auth HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            token.secret
+        )
+        .unwrap();
+    }
+    for mut stream in idle {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+}
+
+#[test]
+fn turns_away_a_bad_token_without_waiting_for_the_body() {
+    let (_dir, port, _token) = start_server(ServerOptions::default());
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    // announce far more body than is ever sent, the answer must not wait on it
+    write!(
+        stream,
+        "POST /validate HTTP/1.1\r\nAuthorization: Bearer forged\r\nContent-Length: 1073741824\r\n\r\n"
+    )
+    .unwrap();
+    stream.write_all(&[b'x'; 16 * 1024]).unwrap();
+    let started = Instant::now();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 401 UNAUTHORISED"),
+        "{}",
+        response
+    );
+    assert!(
+        response.contains("\r\nConnection: close\r\n"),
+        "{}",
+        response
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn rejects_requests_whose_body_framing_is_ambiguous() {
+    let (_dir, port, token) = start_server(ServerOptions::default());
+    let auth = format!("Authorization: Bearer {}\r\n", token.secret);
+
+    let smuggling = [
+        // CL.TE and TE.CL, whichever of the two comes first
+        "Content-Length: 6\r\nTransfer-Encoding: chunked\r\n",
+        "Transfer-Encoding: chunked\r\nContent-Length: 6\r\n",
+        "Content-Length: 6\r\nContent-Length: 6\r\n",
+        "Content-Length: 6\r\nContent-Length: 60\r\n",
+        "Content-Length: six\r\n",
+        "Content-Length : 6\r\n",
+    ];
+    for framing in smuggling {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "POST /auth HTTP/1.1\r\n{}{}\r\n0\r\n\r\nG",
+            auth, framing
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 400 BAD REQUEST"),
+            "{:?}: {}",
+            framing,
+            response
+        );
+    }
+}
+
+#[test]
+fn lets_in_a_request_when_any_of_its_tokens_is_valid() {
+    let (_dir, port, token) = start_server(ServerOptions::default());
+
+    let request = |authorization: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(stream, "GET /auth HTTP/1.1\r\n{}\r\n", authorization).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    // only the second of two headers holds a token we know
+    let response = request(&format!(
+        "Authorization: Bearer forged\r\nAuthorization: Bearer {}\r\n",
+        token.secret
+    ));
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    let response = request(&format!(
+        "Authorization: Bearer forged, Bearer {}\r\n",
+        token.secret
+    ));
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    let response = request("Authorization: Bearer forged, Bearer also-forged\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 401 UNAUTHORISED"),
+        "{}",
+        response
+    );
+
+    let response = request(&format!(
+        "{}Authorization: Bearer {}\r\n",
+        "Authorization: Bearer forged\r\n".repeat(8),
+        token.secret
+    ));
+    assert!(
+        response.starts_with("HTTP/1.1 400 BAD REQUEST"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn answers_only_the_guarded_path() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        guard_path: Some("/auth".to_string()),
+        ..ServerOptions::default()
+    });
+
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let status = |path: &str, secret: &str| client.get(path, Some(secret)).unwrap().status;
+
+    assert_eq!(status("/auth", &token.secret), 200);
+    assert_eq!(status("/auth?from=nginx", &token.secret), 200);
+    assert_eq!(status("/auth", "forged"), 401);
+    assert_eq!(status("/auth/other", &token.secret), 404);
+    assert_eq!(status("/", &token.secret), 404);
+}
+
+#[test]
+fn lets_a_scoped_token_in_only_where_it_is_scoped() {
+    let scoped = TokenOptions {
+        scopes: vec!["GET /api".parse().unwrap(), "* /status".parse().unwrap()],
+        ..TokenOptions::default()
+    };
+    let (_dir, port, token) =
+        start_server_with_token(StoreOptions::default(), scoped, ServerOptions::default());
+
+    // the proxy passes on what was asked of it in the X-Original headers
+    let status = |method: &str, uri: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET /auth HTTP/1.1\r\nAuthorization: Bearer {}\r\nX-Original-Method: {}\r\nX-Original-URI: {}\r\n\r\n",
+            token.secret, method, uri
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    };
+
+    assert_eq!(status("GET", "/api/users?page=2"), "HTTP/1.1 200 OK");
+    assert_eq!(status("GET", "/api"), "HTTP/1.1 200 OK");
+    assert_eq!(status("DELETE", "/status"), "HTTP/1.1 200 OK");
+    assert_eq!(status("POST", "/api/users"), "HTTP/1.1 403 FORBIDDEN");
+    assert_eq!(status("GET", "/apiary"), "HTTP/1.1 403 FORBIDDEN");
+    assert_eq!(status("GET", "/admin"), "HTTP/1.1 403 FORBIDDEN");
+
+    // without scopes a token is let in anywhere
+    let (_dir, port, token) = start_server(ServerOptions::default());
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    assert_eq!(
+        client.get("/admin", Some(&token.secret)).unwrap().status,
+        200
+    );
+}
+
+#[test]
+fn names_the_token_let_in_only_when_asked() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        label_header: true,
+        ..ServerOptions::default()
+    });
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let response = client.get("/auth", Some(&token.secret)).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("X-Auth-Label"), Some("service"));
+    let response = client.get("/auth", Some("forged")).unwrap();
+    assert_eq!(response.status, 401);
+    assert_eq!(response.header("X-Auth-Label"), None);
+
+    let (_dir, port, token) = start_server(ServerOptions::default());
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let response = client.get("/auth", Some(&token.secret)).unwrap();
+    assert_eq!(response.header("X-Auth-Label"), None);
+}
+
+#[test]
+fn answers_traefik_forward_auth_naming_the_user() {
+    let scoped = TokenOptions {
+        scopes: vec!["GET /api".parse().unwrap()],
+        ..TokenOptions::default()
+    };
+    let options = ServerOptions {
+        forward_auth: true,
+        label_header: true,
+        ..ServerOptions::default()
+    };
+    let (_dir, port, token) = start_server_with_token(StoreOptions::default(), scoped, options);
+
+    // as Traefik sends it, the request itself is always a GET of the address
+    let forward_auth = |method: &str, uri: &str, secret: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nAuthorization: Bearer {}\r\n\
+             X-Forwarded-Method: {}\r\nX-Forwarded-Proto: https\r\n\
+             X-Forwarded-Host: app.example.com\r\nX-Forwarded-Uri: {}\r\n\
+             X-Forwarded-For: 203.0.113.7\r\nX-Original-URI: /api\r\n\r\n",
+            port, secret, method, uri
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = forward_auth("GET", "/api/users?page=2", &token.secret);
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(
+        response.contains("\r\nX-Forwarded-User: service\r\n"),
+        "{}",
+        response
+    );
+    assert!(!response.contains("X-Auth-Label"), "{}", response);
+
+    // X-Original-URI is not read in this mode, it could come from the client
+    let response = forward_auth("GET", "/admin", &token.secret);
+    assert!(
+        response.starts_with("HTTP/1.1 403 FORBIDDEN"),
+        "{}",
+        response
+    );
+    let response = forward_auth("GET", "/api", "forged");
+    assert!(
+        response.starts_with("HTTP/1.1 401 UNAUTHORISED"),
+        "{}",
+        response
+    );
+    assert!(!response.contains("X-Forwarded-User"), "{}", response);
+}
+
+#[test]
+fn answers_the_health_path_without_a_token() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        guard_path: Some("/auth".to_string()),
+        health_path: Some("/health".to_string()),
+        ..ServerOptions::default()
+    });
+
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let response = client.get("/health", None).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("application/json"));
+    assert_eq!(response.body, br#"{"status":"ok"}"#);
+    assert_eq!(
+        client
+            .get("/health?probe=1", Some("forged"))
+            .unwrap()
+            .status,
+        200
+    );
+    let response = client.request("HEAD", "/health", None).unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.body.is_empty());
+
+    // every other path is answered as before
+    assert_eq!(client.get("/auth", None).unwrap().status, 401);
+    assert_eq!(
+        client.get("/auth", Some(&token.secret)).unwrap().status,
+        200
+    );
+    assert_eq!(client.get("/healthz", None).unwrap().status, 404);
+}
+
+#[test]
+fn reloads_the_store_for_the_admin_token_only() {
+    let (dir, port, token) = start_server(ServerOptions {
+        admin_token: Some("admin-secret".to_string()),
+        ..ServerOptions::default()
+    });
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let reload = |secret: &str| {
+        client
+            .request("POST", "/admin/reload", Some(secret))
+            .unwrap()
+    };
+
+    // written behind the server's back, as the CLI would
+    let store_path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let mut out_of_band = TokenStore::new(store_path, StoreOptions::default()).unwrap();
+    let added = out_of_band
+        .create("added", TokenOptions::default())
+        .unwrap();
+    assert_eq!(
+        client.get("/auth", Some(&added.secret)).unwrap().status,
+        401
+    );
+
+    // as long as the admin token, and sharing all but its last byte or
+    // running on past it
+    for wrong in ["forged", "admin-secrex", "admin-secre", "admin-secret-2"] {
+        let response = reload(wrong);
+        assert_eq!(response.status, 401, "{}", wrong);
+        assert!(response.body.is_empty(), "{}", wrong);
+    }
+    assert_eq!(reload(&token.secret).status, 401);
+    assert_eq!(
+        client
+            .get("/admin/reload", Some("admin-secret"))
+            .unwrap()
+            .status,
+        401
+    );
+    assert_eq!(
+        client.get("/auth", Some("admin-secret")).unwrap().status,
+        401
+    );
+    // none of those reloaded anything
+    assert_eq!(
+        client.get("/auth", Some(&added.secret)).unwrap().status,
+        401
+    );
+
+    let response = reload("admin-secret");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, br#"{"status":"reloaded"}"#);
+    assert_eq!(
+        client.get("/auth", Some(&added.secret)).unwrap().status,
+        200
+    );
+    assert_eq!(
+        client.get("/auth", Some(&token.secret)).unwrap().status,
+        200
+    );
+
+    // without an admin token the path is guarded like any other
+    let (_dir, port, token) = start_server(ServerOptions::default());
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let response = client
+        .request("POST", "/admin/reload", Some(&token.secret))
+        .unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.body.is_empty());
+}
+
+#[test]
+fn keeps_tokens_added_and_rescinded_while_serving() {
+    let single_use = TokenOptions {
+        max_uses: Some(1),
+        ..TokenOptions::default()
+    };
+    let options = ServerOptions {
+        admin_token: Some("admin-secret".to_string()),
+        ..ServerOptions::default()
+    };
+    let (dir, port, token) = start_server_with_token(StoreOptions::default(), single_use, options);
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let store_path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let open = || TokenStore::new(store_path.clone(), StoreOptions::default()).unwrap();
+
+    let leaked = open().create("leaked", TokenOptions::default()).unwrap();
+    let reload = client.request("POST", "/admin/reload", Some("admin-secret"));
+    assert_eq!(reload.unwrap().status, 200);
+    assert_eq!(
+        client.get("/auth", Some(&leaked.secret)).unwrap().status,
+        200
+    );
+
+    // behind the server's back, then a use it has to write down at once
+    let mut cli = open();
+    cli.rescind("leaked").unwrap();
+    let added = cli.create("added", TokenOptions::default()).unwrap();
+    assert_eq!(
+        client.get("/auth", Some(&token.secret)).unwrap().status,
+        200
+    );
+
+    let reopened = open();
+    assert!(!reopened.contains_token(&leaked.secret).unwrap());
+    assert!(reopened.contains_token(&added.secret).unwrap());
+    assert_eq!(
+        reopened.get("service").unwrap().unwrap().remaining_uses,
+        Some(0)
+    );
+    assert!(reopened.get("leaked").unwrap().is_none());
+}
+
+#[test]
+fn introspects_tokens_for_callers_holding_one() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        introspection: true,
+        ..ServerOptions::default()
+    });
+
+    let introspect = |caller: &str, body: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "POST /introspect HTTP/1.1\r\nAuthorization: Bearer {}\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+            caller,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = introspect(&token.secret, &format!("token={}", token.secret));
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("Content-Type: application/json"));
+    assert!(
+        response.ends_with(r#"{"active":true,"sub":"service"}"#),
+        "{}",
+        response
+    );
+
+    let response = introspect(
+        &token.secret,
+        "token_type_hint=access_token&token=forged%2B1",
+    );
+    assert!(response.ends_with(r#"{"active":false}"#), "{}", response);
+
+    let response = introspect(&token.secret, "nothing=here");
+    assert!(
+        response.starts_with("HTTP/1.1 400 BAD REQUEST"),
+        "{}",
+        response
+    );
+
+    // only holders of a token get to ask about others
+    let response = introspect("forged", &format!("token={}", token.secret));
+    assert!(
+        response.starts_with("HTTP/1.1 401 UNAUTHORISED"),
+        "{}",
+        response
+    );
+
+    let response = introspect(&token.secret, &"a".repeat(9000));
+    assert!(
+        response.starts_with("HTTP/1.1 413 PAYLOAD TOO LARGE"),
+        "{}",
+        response
+    );
+}
+
+/// A clock that panics the first time it is asked once armed, standing in
+/// for a bug somewhere along the way of serving a request.
+struct PanickingClock(AtomicBool);
+
+impl Clock for PanickingClock {
+    fn now(&self) -> Option<u64> {
+        if self.0.swap(false, Ordering::SeqCst) {
+            panic!("clock blew up");
+        }
+        SystemClock.now()
+    }
+}
+
+/// The directory of the server log. A process has only the one log, so it
+/// is shared by every test in this file, and set up by whichever asks first.
+fn log_dir() -> &'static Path {
+    static LOG_DIR: OnceLock<TempDir> = OnceLock::new();
+    LOG_DIR
+        .get_or_init(|| {
+            let dir = tempfile::tempdir().unwrap();
+            logging::log_to_file(dir.path().join("mellon.log")).unwrap();
+            dir
+        })
+        .path()
+}
+
+/// Waits for a line holding `text` to turn up in the log file at `path`.
+/// Access lines are only written once the response has gone out.
+fn logged_to(path: &Path, text: &str) -> bool {
+    (0..100).any(|_| {
+        let found = fs::read_to_string(path).is_ok_and(|log| log.contains(text));
+        if !found {
+            thread::sleep(Duration::from_millis(20));
+        }
+        found
+    })
+}
+
+/// As `logged_to`, for the log wherever it has been moved to.
+fn logged(text: &str) -> bool {
+    (0..100).any(|_| {
+        let found = fs::read_dir(log_dir()).unwrap().any(|entry| {
+            fs::read_to_string(entry.unwrap().path()).is_ok_and(|log| log.contains(text))
+        });
+        if !found {
+            thread::sleep(Duration::from_millis(20));
+        }
+        found
+    })
+}
+
+/// Moves the log aside as logrotate would. The other tests only ever write
+/// to the log, and look for their lines wherever it was moved to.
+#[cfg(unix)]
+#[test]
+fn writes_to_a_fresh_log_file_after_sigusr1() {
+    let log_path = log_dir().join("mellon.log");
+    let rotated_path = log_dir().join("mellon.log.1");
+    let (_dir, port, token) = start_server(ServerOptions {
+        log_format: LogFormat::Clf,
+        ..ServerOptions::default()
+    });
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    let get = |path: &str| client.get(path, Some(&token.secret)).unwrap().status;
+    let access_line = |request: &str| format!("\"GET {} HTTP/1.1\" 200", request);
+
+    assert_eq!(get("/auth?before"), 200);
+    assert!(logged_to(&log_path, &access_line("/auth?before")));
+    fs::rename(&log_path, &rotated_path).unwrap();
+    signal_hook::low_level::raise(signal_hook::consts::SIGUSR1).unwrap();
+    assert_eq!(get("/auth?after"), 200);
+
+    assert!(logged_to(&log_path, &access_line("/auth?after")));
+    let fresh = fs::read_to_string(&log_path).unwrap();
+    let rotated = fs::read_to_string(&rotated_path).unwrap();
+    assert!(!fresh.contains("/auth?before"), "{}", fresh);
+    assert!(!rotated.contains("/auth?after"), "{}", rotated);
+}
+
+/// SIGUSR2 has every server in the process log its stats, these are told
+/// apart by their counts.
+#[cfg(unix)]
+#[test]
+fn counts_what_a_dry_run_would_have_answered() {
+    log_dir();
+    let scoped = TokenOptions {
+        scopes: vec!["GET /only".parse().unwrap()],
+        ..TokenOptions::default()
+    };
+    let dry_run = ServerOptions {
+        dry_run: true,
+        ..ServerOptions::default()
+    };
+    let (_dir, port, token) = start_server_with_token(StoreOptions::default(), scoped, dry_run);
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+    for _ in 0..7 {
+        assert_eq!(client.get("/only", Some("forged")).unwrap().status, 200);
+    }
+    for _ in 0..3 {
+        assert_eq!(
+            client.get("/other", Some(&token.secret)).unwrap().status,
+            200
+        );
+    }
+
+    // stats are logged ahead of the next request
+    signal_hook::low_level::raise(signal_hook::consts::SIGUSR2).unwrap();
+    assert_eq!(
+        client.get("/only", Some(&token.secret)).unwrap().status,
+        200
+    );
+    assert!(logged(
+        "Since start up 0 let in, 7 unauthorised, 3 forbidden, 0 otherwise answered"
+    ));
+}
+
+/// Requests are told apart from those of the other tests by the client's
+/// port, which the server names as the peer.
+#[test]
+fn warns_of_requests_slower_than_the_threshold() {
+    log_dir();
+    let (_dir, port, token) = start_server(ServerOptions {
+        slow_threshold: Some(Duration::from_millis(200)),
+        ..ServerOptions::default()
+    });
+    let request = |delay: Duration| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let client = stream.local_addr().unwrap();
+        thread::sleep(delay);
+        write!(
+            stream,
+            "GET /auth HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            token.secret
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        format!("Slow request from {}: took", client)
+    };
+
+    let fast = request(Duration::ZERO);
+    let slow = request(Duration::from_millis(400));
+    assert!(logged(&slow));
+    // the fast request was logged, if at all, before the slow one started
+    let logs = fs::read_dir(log_dir()).unwrap();
+    assert!(!logs
+        .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+        .any(|log| log.contains(&fast)));
+}
+
+#[test]
+fn answers_head_as_get_less_the_body() {
+    let (_dir, port, token) = start_server(ServerOptions {
+        ok_body: Some(ResponseTemplate::load("welcome {label}", None).unwrap()),
+        unauthorised_body: Some(ResponseTemplate::load("go away", None).unwrap()),
+        ..ServerOptions::default()
+    });
+    let request = |method: &str, secret: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "{} /auth HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            method, secret
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    };
+
+    for (secret, body) in [
+        (token.secret.as_str(), "welcome service"),
+        ("forged", "go away"),
+    ] {
+        let (get_head, get_body) = request("GET", secret);
+        let (head_head, head_body) = request("HEAD", secret);
+        // Content-Length included, it still gives the length of the body left out
+        assert_eq!(head_head, get_head);
+        assert!(get_head.contains(&format!("Content-Length: {}", body.len())));
+        assert_eq!(get_body, body);
+        assert_eq!(head_body, "");
+    }
+}
+
+#[test]
+fn keeps_serving_after_a_request_panics() {
+    let clock = Arc::new(PanickingClock(AtomicBool::new(false)));
+    let store_options = StoreOptions {
+        clock: clock.clone(),
+        ..StoreOptions::default()
+    };
+    let (_dir, port, token) = start_server_with_store(store_options, ServerOptions::default());
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+
+    clock.0.store(true, Ordering::SeqCst);
+    // the connection is dropped without an answer
+    assert!(client.get("/auth", Some(&token.secret)).is_err());
+    assert_eq!(
+        client.get("/auth", Some(&token.secret)).unwrap().status,
+        200
+    );
+}
+
+#[test]
+fn matches_basic_credentials_only_when_accepted() {
+    let basic_server = |accept_basic| {
+        let store_options = StoreOptions {
+            secret_generator: Arc::new(SequenceGenerator::new("secret")),
+            ..StoreOptions::default()
+        };
+        let options = ServerOptions {
+            accept_basic,
+            ..ServerOptions::default()
+        };
+        start_server_with_store(store_options, options)
+    };
+    let request = |port: u16, credentials: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let credentials = match credentials.starts_with("Bearer ") {
+            true => credentials.to_string(),
+            false => format!("Basic {}", credentials),
+        };
+        write!(
+            stream,
+            "GET /auth HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+            credentials
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    // legacy:secret-1, legacy:forged, secret-1 and "no colon"
+    let (_dir, port, _token) = basic_server(Some(BasicSecret::Password));
+    assert!(request(port, "bGVnYWN5OnNlY3JldC0x").starts_with("HTTP/1.1 200 OK"));
+    assert!(request(port, "bGVnYWN5OmZvcmdlZA==").starts_with("HTTP/1.1 401 UNAUTHORISED"));
+    assert!(request(port, "bm8gY29sb24=").starts_with("HTTP/1.1 401 UNAUTHORISED"));
+    assert!(request(port, "not base64!").starts_with("HTTP/1.1 401 UNAUTHORISED"));
+    assert!(request(port, "bGVnYWN5OnNlY3JldC0xA").starts_with("HTTP/1.1 401 UNAUTHORISED"));
+    assert!(request(port, "Bearer secret-1").starts_with("HTTP/1.1 200 OK"));
+    assert!(request(port, "Bearer forged").starts_with("HTTP/1.1 401 UNAUTHORISED"));
+
+    let (_dir, port, _token) = basic_server(Some(BasicSecret::Credentials));
+    assert!(request(port, "bGVnYWN5OnNlY3JldC0x").starts_with("HTTP/1.1 401 UNAUTHORISED"));
+    assert!(request(port, "c2VjcmV0LTE=").starts_with("HTTP/1.1 200 OK"));
+
+    let (_dir, port, _token) = basic_server(None);
+    assert!(request(port, "bGVnYWN5OnNlY3JldC0x").starts_with("HTTP/1.1 401 UNAUTHORISED"));
+}
+
+#[test]
+fn sets_nodelay_on_accepted_connections_as_configured() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    for tcp_nodelay in [true, false] {
+        let _client = TcpStream::connect(addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        accepted.set_nodelay(!tcp_nodelay).unwrap();
+        let options = ServerOptions {
+            tcp_nodelay,
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            ..ServerOptions::default()
+        };
+        options.configure_socket(&accepted).unwrap();
+        assert_eq!(accepted.nodelay().unwrap(), tcp_nodelay);
+    }
+}
+
+/// Serves a token list holding `service:fetched-secret` over HTTP, or a 503
+/// whenever `available` says so, returning the URL to fetch it from.
+/// `available` is asked once per fetch and may take its time answering.
+fn serve_token_list(available: impl Fn() -> bool + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/tokens", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            let body = "# mellon token store\nservice:fetched-secret\n";
+            let response = match available() {
+                true => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                false => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    url
+}
+
+#[test]
+fn asks_for_a_retry_until_the_store_has_loaded() {
+    // the token list is refused until it is made available
+    let available = Arc::new(AtomicBool::new(false));
+    let serving = Arc::clone(&available);
+    let url = serve_token_list(move || serving.load(Ordering::SeqCst));
+
+    let token_store = TokenStore::new(url, StoreOptions::default()).unwrap();
+    assert!(!token_store.is_loaded());
+    let dir = tempfile::tempdir().unwrap();
+    let options = ServerOptions {
+        reload_interval: Some(Duration::from_millis(50)),
+        ..ServerOptions::default()
+    };
+    let port = serve_store(&dir, token_store, options);
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+
+    let response = client.get("/", Some("fetched-secret")).unwrap();
+    assert_eq!(response.status, 503);
+    assert_eq!(response.header("Retry-After"), Some("1"));
+
+    available.store(true, Ordering::SeqCst);
+    let mut status = 503;
+    for _ in 0..100 {
+        thread::sleep(Duration::from_millis(20));
+        status = client.get("/", Some("fetched-secret")).unwrap().status;
+        if status != 503 {
+            break;
+        }
+    }
+    assert_eq!(status, 200);
+}
+
+#[test]
+fn keeps_serving_while_a_slow_fetch_is_under_way() {
+    // the first fetch is answered at once, every later one hangs a while
+    let fetches = Arc::new(AtomicBool::new(false));
+    let url = serve_token_list(move || {
+        if fetches.swap(true, Ordering::SeqCst) {
+            thread::sleep(Duration::from_secs(2));
+        }
+        true
+    });
+    let token_store = TokenStore::new(url, StoreOptions::default()).unwrap();
+    assert!(token_store.is_loaded());
+    let dir = tempfile::tempdir().unwrap();
+    let options = ServerOptions {
+        reload_interval: Some(Duration::from_millis(20)),
+        ..ServerOptions::default()
+    };
+    let port = serve_store(&dir, token_store, options);
+    let client = MellonClient::new(format!("127.0.0.1:{}", port));
+
+    // the first request sets off a fetch, those after it must not wait on it
+    thread::sleep(Duration::from_millis(100));
+    let _ = client.get("/", Some("fetched-secret"));
+    for _ in 0..5 {
+        let started = Instant::now();
+        let response = client.get("/", Some("fetched-secret")).unwrap();
+        assert_eq!(response.status, 200);
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
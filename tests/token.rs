@@ -0,0 +1,83 @@
+use std::{str::FromStr, sync::Arc};
+
+use mellon::tokens::store_format::StoreFormat;
+use mellon::{MockClock, StoreOptions, Token, TokenOptions, TokenStore, TokenStoreError};
+
+#[test]
+fn rejects_labels_and_secrets_the_store_cannot_hold() {
+    assert!(Token::new("service", "secret").is_ok());
+    assert!(matches!(
+        Token::new("", "secret"),
+        Err(TokenStoreError::EmptyLabel)
+    ));
+    assert!(matches!(
+        Token::new("service", ""),
+        Err(TokenStoreError::EmptySecret)
+    ));
+    // a colon only ends the label early in the colon format
+    let colon_label = Token::new("ser:vice", "secret").unwrap();
+    assert!(StoreFormat::Colon.token_line(&colon_label).is_err());
+    assert!(StoreFormat::Jsonl.token_line(&colon_label).is_ok());
+    assert!(matches!(
+        Token::new("service\n", "secret"),
+        Err(TokenStoreError::LineBreak)
+    ));
+    assert!(matches!(
+        Token::new("service", "sec\r\nret"),
+        Err(TokenStoreError::LineBreak)
+    ));
+    // secrets are free to hold colons, only the first one splits the line
+    assert_eq!(
+        Token::from_str("service:sec:ret").unwrap().secret,
+        "sec:ret"
+    );
+    assert!(Token::from_str(":secret").is_err());
+}
+
+#[test]
+fn creates_only_valid_tokens() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let mut token_store = TokenStore::new(path, StoreOptions::default()).unwrap();
+
+    assert!(matches!(
+        token_store.create("ser:vice", TokenOptions::default()),
+        Err(TokenStoreError::ColonInLabel)
+    ));
+    assert!(matches!(
+        token_store.create_with_secret("service", "two\nlines", TokenOptions::default()),
+        Err(TokenStoreError::LineBreak)
+    ));
+    assert!(token_store.is_empty());
+}
+
+#[test]
+fn reads_lines_with_and_without_a_creation_time() {
+    let old = Token::from_str("service:secret").unwrap();
+    assert_eq!(old.created_at, None);
+    assert_eq!(old.to_string(), "service:secret");
+
+    let line = r#"service:secret:{"created_at":1700000000}"#;
+    let new = Token::from_str(line).unwrap();
+    assert_eq!(new.created_at, Some(1_700_000_000));
+    assert_eq!(new.to_string(), line);
+}
+
+#[test]
+fn stamps_new_tokens_with_the_time_they_were_created() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let options = || StoreOptions {
+        clock: Arc::new(MockClock::new(1_700_000_000)),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+    let token = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+    assert_eq!(token.created_at, Some(1_700_000_000));
+
+    let token_store = TokenStore::new(path, options()).unwrap();
+    let service = token_store.get("service").unwrap().unwrap();
+    assert_eq!(service.created_at, Some(1_700_000_000));
+}
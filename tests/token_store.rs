@@ -0,0 +1,1150 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use mellon::tokens::store_diff::{DesiredToken, StoreDiff};
+use mellon::tokens::store_format::StoreFormat;
+use mellon::tokens::store_fsck::{Finding, StoreCheck};
+use mellon::tokens::store_key::StoreKey;
+use mellon::tokens::token::{hash_secret, MatchKind};
+use mellon::tokens::token_store::Reload;
+use mellon::{
+    MockClock, RandomGenerator, SecretFormat, SequenceGenerator, SigningKey, StoreLayout,
+    StoreOptions, TokenOptions, TokenStore, TokenStoreError, TokenUse,
+};
+
+#[test]
+fn validates_a_token_from_a_reopened_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    let token = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+
+    let token_store = TokenStore::new(path, StoreOptions::default()).unwrap();
+    assert!(token_store.contains_token(&token.secret).unwrap());
+    assert_eq!(
+        token_store.use_token(&token.secret).unwrap(),
+        TokenUse::Allowed("service".to_string())
+    );
+    assert_eq!(
+        token_store.use_token("not a token").unwrap(),
+        TokenUse::Unknown
+    );
+}
+
+#[test]
+fn refuses_labels_and_secrets_over_the_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let options = StoreOptions {
+        max_label_len: 8,
+        max_secret_len: 12,
+        ..StoreOptions::default()
+    };
+
+    let mut token_store = TokenStore::new(path, options).unwrap();
+    assert!(token_store
+        .create_with_secret("8 bytes!", "twelve bytes", TokenOptions::default())
+        .is_ok());
+    assert!(matches!(
+        token_store.create_with_secret("9 bytes!!", "short", TokenOptions::default()),
+        Err(TokenStoreError::LabelTooLong(8))
+    ));
+    assert!(matches!(
+        token_store.create_with_secret("label", "thirteen byte", TokenOptions::default()),
+        Err(TokenStoreError::SecretTooLong(12))
+    ));
+}
+
+#[test]
+fn verifies_signed_tokens_and_rejects_forgeries() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let signed_store = |secret: &[u8]| StoreOptions {
+        signing_key: Some(SigningKey::new(secret).unwrap()),
+        ..StoreOptions::default()
+    };
+
+    let mut token_store = TokenStore::new(path.clone(), signed_store(b"server key")).unwrap();
+    let token = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+    token_store
+        .create("other", TokenOptions::default())
+        .unwrap();
+    let genuine = token_store.sign("service").unwrap();
+    let (_, signature) = genuine.rsplit_once('.').unwrap();
+    let (kept, last) = signature.split_at(signature.len() - 1);
+    let flipped = if last == "0" { "1" } else { "0" };
+    assert!(token_store.contains_token(&genuine).unwrap());
+
+    // the stored key id is not a secret and must not let anyone in
+    assert!(!token_store.contains_token(&token.secret).unwrap());
+    // another label's signature, a tampered one and a truncated one
+    let forgeries = [
+        format!("other.{}", signature),
+        format!("service.{}{}", kept, flipped),
+        format!("service.{}", &signature[..signature.len() - 2]),
+        "service.".to_string(),
+        "service".to_string(),
+    ];
+    for forged in forgeries.iter() {
+        assert!(!token_store.contains_token(forged).unwrap(), "{}", forged);
+    }
+
+    // a different key does not accept tokens signed with the first
+    let rekeyed = TokenStore::new(path, signed_store(b"another key")).unwrap();
+    assert!(!rekeyed.contains_token(&genuine).unwrap());
+}
+
+#[test]
+fn expires_tokens_and_compacts_tombstones_as_the_clock_moves() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let clock = Arc::new(MockClock::new(1_000));
+    let options = StoreOptions {
+        clock: clock.clone(),
+        ..StoreOptions::default()
+    };
+
+    let mut token_store = TokenStore::new(path, options).unwrap();
+    let token = token_store
+        .create(
+            "short-lived",
+            TokenOptions {
+                expires_at: Some(1_060),
+                ..TokenOptions::default()
+            },
+        )
+        .unwrap();
+    let allowed = TokenUse::Allowed("short-lived".to_string());
+    assert_eq!(token_store.use_token(&token.secret).unwrap(), allowed);
+    clock.advance(59);
+    assert_eq!(token_store.use_token(&token.secret).unwrap(), allowed);
+    clock.advance(1);
+    assert_eq!(
+        token_store.use_token(&token.secret).unwrap(),
+        TokenUse::Expired("short-lived".to_string())
+    );
+
+    token_store.rescind_with_tombstone("short-lived").unwrap();
+    assert_eq!(
+        token_store.tombstones().next().unwrap().revoked_at,
+        Some(1_060)
+    );
+    assert_eq!(token_store.compact(1_060).unwrap(), 0);
+    clock.set(2_000);
+    assert_eq!(
+        token_store
+            .compact(token_store.now().unwrap() - 60)
+            .unwrap(),
+        1
+    );
+}
+
+#[test]
+fn stops_containing_a_token_once_its_ttl_is_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let clock = Arc::new(MockClock::new(1_000));
+    let options = || StoreOptions {
+        clock: clock.clone(),
+        ..StoreOptions::default()
+    };
+
+    let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+    let expiring = token_store
+        .create_with_ttl("ci", Duration::from_secs(60))
+        .unwrap();
+    let lasting = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+    assert_eq!(expiring.expires_at, Some(1_060));
+
+    // both kinds of token come back from the file as they went in
+    let token_store = TokenStore::new(path, options()).unwrap();
+    assert_eq!(
+        token_store.get("ci").unwrap().unwrap().expires_at,
+        Some(1_060)
+    );
+    assert_eq!(
+        token_store.get("service").unwrap().unwrap().expires_at,
+        None
+    );
+
+    clock.set(1_059);
+    assert!(token_store.contains_token(&expiring.secret).unwrap());
+    clock.set(1_060);
+    assert!(!token_store.contains_token(&expiring.secret).unwrap());
+    assert!(token_store.contains_token(&lasting.secret).unwrap());
+}
+
+#[test]
+fn keeps_expired_tokens_on_disk_unless_pruned() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let clock = Arc::new(MockClock::new(1_000));
+    let options = || StoreOptions {
+        clock: clock.clone(),
+        ..StoreOptions::default()
+    };
+
+    let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+    let expiring = |expires_at| TokenOptions {
+        expires_at,
+        ..TokenOptions::default()
+    };
+    let expired = token_store
+        .create("expired", expiring(Some(1_000)))
+        .unwrap();
+    token_store
+        .create("expiring", expiring(Some(2_000)))
+        .unwrap();
+    token_store.create("lasting", expiring(None)).unwrap();
+
+    // by default an expired token is kept, but not let in
+    let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+    assert_eq!(token_store.iter().unwrap().count(), 3);
+    assert_eq!(
+        token_store.use_token(&expired.secret).unwrap(),
+        TokenUse::Expired("expired".to_string())
+    );
+
+    assert_eq!(token_store.prune_expired().unwrap(), 1);
+    assert_eq!(token_store.prune_expired().unwrap(), 0);
+    let token_store = TokenStore::new(path, options()).unwrap();
+    let mut labels: Vec<_> = token_store
+        .iter()
+        .unwrap()
+        .map(|token| token.label.as_str())
+        .collect();
+    labels.sort();
+    assert_eq!(labels, ["expiring", "lasting"]);
+}
+
+#[test]
+fn leaves_the_store_intact_when_a_write_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    let token = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+    let before = std::fs::read_to_string(&path).unwrap();
+
+    // a directory where the new store would be written makes every attempt
+    // fail, whoever the tests run as
+    let partial = dir
+        .path()
+        .join(format!(".tokens.{}.partial", std::process::id()));
+    std::fs::create_dir(&partial).unwrap();
+    assert!(matches!(
+        token_store.create("web", TokenOptions::default()),
+        Err(TokenStoreError::Io { .. })
+    ));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+    assert!(token_store.contains_token(&token.secret).unwrap());
+
+    std::fs::remove_dir(&partial).unwrap();
+    token_store.create("web", TokenOptions::default()).unwrap();
+    let left = std::fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(left, 1, "only the store itself is left behind");
+}
+
+#[test]
+fn rolls_back_changes_that_could_not_be_written() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    let token = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+
+    // every write fails while the partial path is taken by a directory
+    let partial = dir
+        .path()
+        .join(format!(".tokens.{}.partial", std::process::id()));
+    std::fs::create_dir(&partial).unwrap();
+    let created = token_store.create("web", TokenOptions::default());
+    assert!(matches!(created, Err(TokenStoreError::Io { .. })));
+    assert!(token_store.get("web").unwrap().is_none());
+    assert!(matches!(
+        token_store.rescind("service"),
+        Err(TokenStoreError::Io { .. })
+    ));
+    assert!(matches!(
+        token_store.rescind_with_tombstone("service"),
+        Err(TokenStoreError::Io { .. })
+    ));
+    assert!(token_store.contains_token(&token.secret).unwrap());
+    assert_eq!(token_store.tombstones().count(), 0);
+    let labels: Vec<_> = token_store
+        .iter()
+        .unwrap()
+        .map(|t| t.label.clone())
+        .collect();
+    assert_eq!(labels, ["service"]);
+
+    // and what is held in memory is what the next write puts on disk
+    std::fs::remove_dir(&partial).unwrap();
+    token_store.rescind("service").unwrap();
+    let reopened = TokenStore::new(path, StoreOptions::default()).unwrap();
+    assert!(reopened.is_empty());
+}
+
+#[test]
+fn reports_a_store_directory_that_cannot_be_created() {
+    let dir = tempfile::tempdir().unwrap();
+    // a file in the way stops the directory being made, whoever the tests
+    // run as
+    let blocker = dir.path().join("not-a-dir");
+    std::fs::write(&blocker, "").unwrap();
+    let path = blocker.join("store").join("tokens");
+
+    let opened = TokenStore::new(path.to_string_lossy().into_owned(), StoreOptions::default());
+    let Err(err @ TokenStoreError::Io { .. }) = opened else {
+        panic!("expected an I/O error, got {:?}", opened.map(|_| ()));
+    };
+    assert!(
+        err.to_string()
+            .starts_with("Unable to create store directory"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn leaves_unmarked_files_alone_unless_forced() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens");
+    let legacy = "legacy:a-secret-from-before-markers\n";
+    std::fs::write(&path, legacy).unwrap();
+    let path = path.to_string_lossy().into_owned();
+
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    assert!(token_store
+        .contains_token("a-secret-from-before-markers")
+        .unwrap());
+    assert!(matches!(
+        token_store.create("service", TokenOptions::default()),
+        Err(TokenStoreError::NotAStore(_))
+    ));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), legacy);
+
+    let options = StoreOptions {
+        force_overwrite: true,
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options).unwrap();
+    let token = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+
+    // once marked, the store is written to without further ado
+    let mut token_store = TokenStore::new(path, StoreOptions::default()).unwrap();
+    assert!(token_store.contains_token(&token.secret).unwrap());
+    assert!(token_store
+        .contains_token("a-secret-from-before-markers")
+        .unwrap());
+    token_store.rescind("legacy").unwrap();
+}
+
+#[test]
+fn counts_uses_in_memory_when_the_file_is_not_a_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens");
+    let legacy = "trial:trial-secret:{\"remaining_uses\":2}\n";
+    std::fs::write(&path, legacy).unwrap();
+    let path = path.to_string_lossy().into_owned();
+
+    // a write that can never get through is not tried again and again
+    let token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    let started = Instant::now();
+    let allowed = TokenUse::Allowed("trial".to_string());
+    assert_eq!(token_store.use_token("trial-secret").unwrap(), allowed);
+    assert_eq!(token_store.use_token("trial-secret").unwrap(), allowed);
+    assert_eq!(
+        token_store.use_token("trial-secret").unwrap(),
+        TokenUse::Exhausted("trial".to_string())
+    );
+    assert!(started.elapsed() < Duration::from_millis(50));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), legacy);
+}
+
+#[test]
+fn keeps_labels_the_colon_format_cannot_hold_in_jsonl() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let jsonl = || StoreOptions {
+        format: StoreFormat::Jsonl,
+        ..StoreOptions::default()
+    };
+    let labels = [
+        "ci:deploy",
+        "with spaces",
+        "ünïcödé ✓",
+        "{\"looks\": \"like json\"}",
+    ];
+    let mut token_store = TokenStore::new(path.clone(), jsonl()).unwrap();
+    let tokens: Vec<_> = labels
+        .iter()
+        .map(|label| token_store.create(label, TokenOptions::default()).unwrap())
+        .collect();
+    token_store.rescind_with_tombstone("ci:deploy").unwrap();
+
+    let token_store = TokenStore::new(path.clone(), jsonl()).unwrap();
+    assert_eq!(
+        token_store.use_token(&tokens[0].secret).unwrap(),
+        TokenUse::Unknown
+    );
+    assert_eq!(token_store.tombstones().next().unwrap().label, "ci:deploy");
+    for token in &tokens[1..] {
+        assert_eq!(
+            token_store.use_token(&token.secret).unwrap(),
+            TokenUse::Allowed(token.label.clone())
+        );
+    }
+
+    // the colon format would split the tombstone's label, so the store is
+    // left as it is rather than switched over
+    let before = std::fs::read_to_string(&path).unwrap();
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    assert!(token_store.migrate().is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+    assert!(matches!(
+        token_store.create("ops:oncall", TokenOptions::default()),
+        Err(TokenStoreError::ColonInLabel)
+    ));
+}
+
+#[test]
+fn round_trips_tokens_in_either_format() {
+    for format in [StoreFormat::Colon, StoreFormat::Jsonl] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens").to_string_lossy().into_owned();
+        let options = || StoreOptions {
+            format,
+            clock: Arc::new(MockClock::new(1_000)),
+            ..StoreOptions::default()
+        };
+
+        let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+        token_store
+            .create("plain", TokenOptions::default())
+            .unwrap();
+        token_store
+            .create(
+                "annotated",
+                TokenOptions {
+                    annotations: [("owner".to_string(), "ops team".to_string())].into(),
+                    max_uses: Some(3),
+                    scopes: vec!["GET /status".parse().unwrap()],
+                    expires_at: Some(2_000),
+                    ..TokenOptions::default()
+                },
+            )
+            .unwrap();
+        token_store
+            .create("rescinded", TokenOptions::default())
+            .unwrap();
+        token_store.rescind_with_tombstone("rescinded").unwrap();
+        let sorted = |token_store: &TokenStore| {
+            let mut tokens: Vec<_> = token_store.iter().unwrap().cloned().collect();
+            tokens.sort_by(|a, b| a.label.cmp(&b.label));
+            tokens
+        };
+        let written = sorted(&token_store);
+
+        // the format is told from the file, not from the options
+        let other = match format {
+            StoreFormat::Colon => StoreFormat::Jsonl,
+            StoreFormat::Jsonl => StoreFormat::Colon,
+        };
+        let token_store = TokenStore::new(
+            path,
+            StoreOptions {
+                format: other,
+                ..options()
+            },
+        )
+        .unwrap();
+        assert_eq!(token_store.layout().unwrap().format, Some(format));
+        assert_eq!(sorted(&token_store), written, "{:?}", format);
+        let tombstones: Vec<_> = token_store
+            .tombstones()
+            .map(|tombstone| (tombstone.label.as_str(), tombstone.revoked_at))
+            .collect();
+        assert_eq!(tombstones, [("rescinded", Some(1_000))], "{:?}", format);
+    }
+}
+
+#[test]
+fn refuses_secrets_the_colon_format_would_read_back_otherwise() {
+    let secrets = [
+        "cut:{short}",
+        ":1700000000",
+        ":looks-like-a-tombstone",
+        " padded",
+        "padded\t",
+    ];
+    for format in [StoreFormat::Colon, StoreFormat::Jsonl] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens").to_string_lossy().into_owned();
+        let options = || StoreOptions {
+            format,
+            ..StoreOptions::default()
+        };
+        let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+        for (index, secret) in secrets.iter().enumerate() {
+            let label = format!("token-{}", index);
+            let created = token_store.create_with_secret(&label, secret, TokenOptions::default());
+            match format {
+                StoreFormat::Colon => assert!(
+                    matches!(created, Err(TokenStoreError::UnreadableSecret)),
+                    "{:?}",
+                    secret
+                ),
+                StoreFormat::Jsonl => assert!(created.is_ok(), "{:?}", secret),
+            }
+        }
+
+        // whatever made it in reads back as it was written
+        let token_store = TokenStore::new(path, options()).unwrap();
+        for (index, secret) in secrets.iter().enumerate() {
+            let expected = match format {
+                StoreFormat::Colon => TokenUse::Unknown,
+                StoreFormat::Jsonl => TokenUse::Allowed(format!("token-{}", index)),
+            };
+            assert_eq!(
+                token_store.use_token(secret).unwrap(),
+                expected,
+                "{:?}",
+                secret
+            );
+        }
+        assert_eq!(token_store.tombstones().count(), 0);
+    }
+}
+
+#[test]
+fn reports_the_line_of_the_file_a_finding_is_on() {
+    for (marked, duplicate_line) in [(true, 4), (false, 3)] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens");
+        let marker = if marked { "# mellon token store\n" } else { "" };
+        let contents = "first:a-secret\nsecond:b-secret\nthird:a-secret\nfirst:c-secret\n";
+        std::fs::write(&path, format!("{}{}", marker, contents)).unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let token_store = TokenStore::new(path, StoreOptions::default()).unwrap();
+        let check = StoreCheck::run(&token_store).unwrap();
+        let lines: Vec<_> = check
+            .findings
+            .iter()
+            .map(|finding| match finding {
+                Finding::DuplicateSecret { line, .. } | Finding::DuplicateLabel { line, .. } => {
+                    *line
+                }
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [duplicate_line, duplicate_line + 1],
+            "marked: {}",
+            marked
+        );
+    }
+}
+
+#[test]
+fn migrates_an_unmarked_store_to_the_current_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens");
+    std::fs::write(
+        &path,
+        "legacy:a-secret-from-before-markers\nrescinded::1700000000\n",
+    )
+    .unwrap();
+    let path = path.to_string_lossy().into_owned();
+
+    let options = StoreOptions {
+        format: StoreFormat::Jsonl,
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options).unwrap();
+    assert_eq!(
+        token_store.layout().unwrap(),
+        StoreLayout {
+            marked: false,
+            format: Some(StoreFormat::Colon)
+        }
+    );
+    assert!(!token_store.is_current().unwrap());
+
+    token_store.migrate().unwrap();
+    assert!(token_store.is_current().unwrap());
+    let token_store = TokenStore::new(path, StoreOptions::default()).unwrap();
+    assert_eq!(
+        token_store.layout().unwrap(),
+        StoreLayout {
+            marked: true,
+            format: Some(StoreFormat::Jsonl)
+        }
+    );
+    assert!(token_store
+        .contains_token("a-secret-from-before-markers")
+        .unwrap());
+    assert_eq!(token_store.tombstones().count(), 1);
+}
+
+#[test]
+fn applies_a_diff_in_full_or_not_at_all() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let clock = Arc::new(MockClock::new(1_000));
+    let options = || StoreOptions {
+        clock: clock.clone(),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+    let annotated = TokenOptions {
+        annotations: [("owner".to_string(), "ops".to_string())].into(),
+        ..TokenOptions::default()
+    };
+    token_store
+        .create_with_secret("changed", "old-secret", annotated)
+        .unwrap();
+    token_store
+        .create_with_secret("dropped", "dropped-secret", TokenOptions::default())
+        .unwrap();
+    clock.advance(500);
+
+    // the added token takes a secret the update leaves held
+    let desired = DesiredToken::parse_all("changed:new-secret\nadded:new-secret\n").unwrap();
+    let diff = StoreDiff::between(&token_store, desired).unwrap();
+    let before = std::fs::read_to_string(&path).unwrap();
+    assert!(diff.apply(&mut token_store).is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+    assert!(token_store.contains_token("old-secret").unwrap());
+    assert!(token_store.contains_token("dropped-secret").unwrap());
+    assert!(token_store.get("added").unwrap().is_none());
+
+    let desired = DesiredToken::parse_all("changed:new-secret\nadded:added-secret\n").unwrap();
+    let diff = StoreDiff::between(&token_store, desired).unwrap();
+    diff.apply(&mut token_store).unwrap();
+    let token_store = TokenStore::new(path, options()).unwrap();
+    assert!(!token_store.contains_token("old-secret").unwrap());
+    assert!(token_store.get("dropped").unwrap().is_none());
+    let changed = token_store.get("changed").unwrap().unwrap();
+    assert_eq!(changed.secret, "new-secret");
+    assert_eq!(changed.created_at, Some(1_000));
+    assert_eq!(changed.annotations["owner"], "ops");
+    let added = token_store.get("added").unwrap().unwrap();
+    assert_eq!(added.created_at, Some(1_500));
+}
+
+#[test]
+fn creates_a_batch_skipping_labels_already_taken() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let options = StoreOptions {
+        secret_generator: Arc::new(SequenceGenerator::new("secret")),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options).unwrap();
+    let taken = token_store
+        .create("taken", TokenOptions::default())
+        .unwrap();
+    assert_eq!(taken.secret, "secret-1");
+
+    let labels = ["one", "taken", "two", "one", "bad:label"].map(String::from);
+    let created = token_store
+        .create_batch(&labels, &TokenOptions::default())
+        .unwrap();
+    assert_eq!(created[0].as_ref().unwrap().secret, "secret-2");
+    assert!(matches!(&created[1], Err(TokenStoreError::DuplicateLabel(label)) if label == "taken"));
+    assert!(matches!(&created[3], Err(TokenStoreError::DuplicateLabel(label)) if label == "one"));
+    assert!(matches!(created[4], Err(TokenStoreError::ColonInLabel)));
+
+    let token_store = TokenStore::new(path, StoreOptions::default()).unwrap();
+    // skipped labels still draw a secret, so the sequence moves on for them
+    assert_eq!(
+        token_store.use_token("secret-4").unwrap(),
+        TokenUse::Allowed("two".to_string())
+    );
+    assert_eq!(token_store.iter().unwrap().count(), 3);
+}
+
+#[test]
+fn generates_secrets_in_each_format() {
+    let formats = [
+        (SecretFormat::Uuid, 16, 36),
+        (SecretFormat::Hex, 16, 32),
+        (SecretFormat::Hex, 33, 66),
+        (SecretFormat::Base64, 16, 22),
+        (SecretFormat::Base64, 32, 43),
+    ];
+    for (format, bytes, len) in formats {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens").to_string_lossy().into_owned();
+        let options = || StoreOptions {
+            secret_generator: Arc::new(RandomGenerator::new(format, bytes)),
+            ..StoreOptions::default()
+        };
+        let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+        let token = token_store
+            .create("service", TokenOptions::default())
+            .unwrap();
+        assert_eq!(token.secret.len(), len, "{:?}: {}", format, token.secret);
+        let allowed = match format {
+            SecretFormat::Uuid => |c: char| c.is_ascii_hexdigit() || c == '-',
+            SecretFormat::Hex => |c: char| c.is_ascii_hexdigit(),
+            SecretFormat::Base64 => |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_',
+        };
+        assert!(token.secret.chars().all(allowed), "{}", token.secret);
+
+        let token_store = TokenStore::new(path, options()).unwrap();
+        assert_eq!(
+            token_store.use_token(&token.secret).unwrap(),
+            TokenUse::Allowed("service".to_string())
+        );
+    }
+}
+
+#[test]
+fn keeps_only_a_hash_of_secrets_when_asked() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let options = StoreOptions {
+        hash_secrets: true,
+        secret_generator: Arc::new(SequenceGenerator::new("secret")),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options).unwrap();
+    let token = token_store.create("ci", TokenOptions::default()).unwrap();
+    assert_eq!(token.secret, "secret-1");
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(!contents.contains("secret-1"), "{}", contents);
+    assert!(contents.contains(&hash_secret("secret-1")), "{}", contents);
+
+    // hashed tokens keep verifying without the option, it only affects
+    // tokens created from then on
+    let mut token_store = TokenStore::new(path, StoreOptions::default()).unwrap();
+    assert_eq!(
+        token_store.get("ci").unwrap().unwrap().matching,
+        MatchKind::Hashed
+    );
+    assert_eq!(
+        token_store.use_token("secret-1").unwrap(),
+        TokenUse::Allowed("ci".to_string())
+    );
+    assert_eq!(
+        token_store.use_token(&hash_secret("secret-1")).unwrap(),
+        TokenUse::Unknown
+    );
+    let token = token_store.create("web", TokenOptions::default()).unwrap();
+    assert_eq!(
+        token_store.get("web").unwrap().unwrap().secret,
+        token.secret
+    );
+}
+
+#[test]
+fn runs_out_after_the_last_use_even_across_a_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    let limited = TokenOptions {
+        max_uses: Some(3),
+        ..TokenOptions::default()
+    };
+    let token = token_store.create("trial", limited).unwrap();
+    let allowed = TokenUse::Allowed("trial".to_string());
+
+    // the first use is written out, the second only counted in memory
+    assert_eq!(token_store.use_token(&token.secret).unwrap(), allowed);
+    assert_eq!(token_store.use_token(&token.secret).unwrap(), allowed);
+    let reopened = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    assert_eq!(
+        reopened.get("trial").unwrap().unwrap().remaining_uses,
+        Some(2)
+    );
+
+    // reading the file again must not hand back the use it has yet to see
+    token_store.reload().unwrap();
+    assert!(token_store.contains_token(&token.secret).unwrap());
+    assert_eq!(token_store.use_token(&token.secret).unwrap(), allowed);
+    assert_eq!(
+        token_store.use_token(&token.secret).unwrap(),
+        TokenUse::Exhausted("trial".to_string())
+    );
+    assert!(!token_store.contains_token(&token.secret).unwrap());
+
+    let reopened = TokenStore::new(path, StoreOptions::default()).unwrap();
+    assert_eq!(
+        reopened.use_token(&token.secret).unwrap(),
+        TokenUse::Exhausted("trial".to_string())
+    );
+    assert!(!reopened.contains_token(&token.secret).unwrap());
+}
+
+#[test]
+fn keeps_flushed_uses_through_unrelated_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    let once = TokenOptions {
+        max_uses: Some(1),
+        ..TokenOptions::default()
+    };
+    let token = token_store.create("once", once).unwrap();
+    let thrice = TokenOptions {
+        max_uses: Some(3),
+        ..TokenOptions::default()
+    };
+    let trial = token_store.create("trial", thrice).unwrap();
+
+    // the last use of one is written at once, the other is only counted
+    assert!(matches!(
+        token_store.use_token(&token.secret).unwrap(),
+        TokenUse::Allowed(_)
+    ));
+    assert!(matches!(
+        token_store.use_token(&trial.secret).unwrap(),
+        TokenUse::Allowed(_)
+    ));
+    token_store
+        .create("other", TokenOptions::default())
+        .unwrap();
+    token_store.rescind("other").unwrap();
+    assert_eq!(
+        token_store.use_token(&token.secret).unwrap(),
+        TokenUse::Exhausted("once".to_string())
+    );
+
+    let reopened = TokenStore::new(path, StoreOptions::default()).unwrap();
+    assert_eq!(
+        reopened.use_token(&token.secret).unwrap(),
+        TokenUse::Exhausted("once".to_string())
+    );
+    assert_eq!(
+        reopened.get("trial").unwrap().unwrap().remaining_uses,
+        Some(2)
+    );
+}
+
+#[test]
+fn records_when_a_token_was_last_used() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let clock = Arc::new(MockClock::new(1_000));
+    let options = || StoreOptions {
+        clock: clock.clone(),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+    let token = token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+    assert_eq!(token_store.last_used_at("service"), None);
+
+    // the first use is written out, later ones wait for the next flush
+    token_store.use_token(&token.secret).unwrap();
+    clock.set(1_030);
+    token_store.use_token(&token.secret).unwrap();
+    assert_eq!(token_store.last_used_at("service"), Some(1_030));
+    let reopened = TokenStore::new(path.clone(), options()).unwrap();
+    assert_eq!(
+        reopened.get("service").unwrap().unwrap().last_used_at,
+        Some(1_000)
+    );
+
+    // writing the store for some other reason takes the latest use along
+    token_store
+        .create("other", TokenOptions::default())
+        .unwrap();
+    assert_eq!(token_store.last_used_at("service"), Some(1_030));
+    let reopened = TokenStore::new(path, options()).unwrap();
+    assert_eq!(
+        reopened.get("service").unwrap().unwrap().last_used_at,
+        Some(1_030)
+    );
+    assert_eq!(reopened.get("other").unwrap().unwrap().last_used_at, None);
+}
+
+#[test]
+fn rotates_a_secret_keeping_the_rest_of_the_token() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let options = || StoreOptions {
+        secret_generator: Arc::new(SequenceGenerator::new("secret")),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), options()).unwrap();
+    let limited = TokenOptions {
+        max_uses: Some(5),
+        ..TokenOptions::default()
+    };
+    let token = token_store.create("deploy", limited).unwrap();
+    // the first use is written out at once, the second only counted
+    token_store.use_token(&token.secret).unwrap();
+    token_store.use_token(&token.secret).unwrap();
+
+    let rotated = token_store.rotate("deploy").unwrap();
+    assert_eq!(rotated.secret, "secret-2");
+    assert_eq!(rotated.remaining_uses, Some(3));
+    assert_eq!(
+        token_store.use_token(&token.secret).unwrap(),
+        TokenUse::Unknown
+    );
+    assert!(token_store.contains_token(&rotated.secret).unwrap());
+    for _ in 0..3 {
+        assert_eq!(
+            token_store.use_token(&rotated.secret).unwrap(),
+            TokenUse::Allowed("deploy".to_string())
+        );
+    }
+    assert_eq!(
+        token_store.use_token(&rotated.secret).unwrap(),
+        TokenUse::Exhausted("deploy".to_string())
+    );
+
+    let token_store = TokenStore::new(path, options()).unwrap();
+    assert_eq!(
+        token_store.use_token(&token.secret).unwrap(),
+        TokenUse::Unknown
+    );
+    let deploy = token_store.get("deploy").unwrap().unwrap();
+    assert_eq!(deploy.secret, "secret-2");
+    assert_eq!(deploy.remaining_uses, Some(0));
+
+    let mut token_store = token_store;
+    assert!(matches!(
+        token_store.rotate("missing"),
+        Err(TokenStoreError::NotFound(_))
+    ));
+    let pattern = TokenOptions {
+        matching: MatchKind::Regex,
+        ..TokenOptions::default()
+    };
+    token_store
+        .create_with_secret("workers", "worker-[0-9]+", pattern)
+        .unwrap();
+    assert!(matches!(
+        token_store.rotate("workers"),
+        Err(TokenStoreError::NotRotatable(_))
+    ));
+}
+
+#[test]
+fn follows_a_secret_moved_to_another_label_on_refresh() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    token_store
+        .create_with_secret("old-name", "moving-secret", TokenOptions::default())
+        .unwrap();
+    token_store
+        .create_with_secret("staying", "staying-secret", TokenOptions::default())
+        .unwrap();
+    token_store
+        .create_with_secret("swap-a", "swap-secret-a", TokenOptions::default())
+        .unwrap();
+    token_store
+        .create_with_secret("swap-b", "swap-secret-b", TokenOptions::default())
+        .unwrap();
+    let mut token_store = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+
+    // the secret goes to a new label, and two labels trade secrets
+    let mut editor = TokenStore::new(path.clone(), StoreOptions::default()).unwrap();
+    editor.rescind("old-name").unwrap();
+    editor
+        .create_with_secret("new-name", "moving-secret", TokenOptions::default())
+        .unwrap();
+    editor.rescind("swap-a").unwrap();
+    editor.rescind("swap-b").unwrap();
+    editor
+        .create_with_secret("swap-a", "swap-secret-b", TokenOptions::default())
+        .unwrap();
+    editor
+        .create_with_secret("swap-b", "swap-secret-a", TokenOptions::default())
+        .unwrap();
+
+    assert!(matches!(
+        token_store.refresh().unwrap(),
+        Reload::Incremental { .. }
+    ));
+    let expected = [
+        ("moving-secret", "new-name"),
+        ("staying-secret", "staying"),
+        ("swap-secret-a", "swap-b"),
+        ("swap-secret-b", "swap-a"),
+    ];
+    for (secret, label) in expected {
+        assert_eq!(
+            token_store.use_token(secret).unwrap(),
+            TokenUse::Allowed(label.to_string()),
+            "{}",
+            secret
+        );
+    }
+    assert!(token_store.get("old-name").unwrap().is_none());
+}
+
+#[test]
+fn rescinds_the_token_holding_a_secret() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let options = StoreOptions {
+        hash_secrets: true,
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path, options).unwrap();
+    let token = token_store
+        .create("leaked", TokenOptions::default())
+        .unwrap();
+    let pattern = TokenOptions {
+        matching: MatchKind::Regex,
+        ..TokenOptions::default()
+    };
+    token_store
+        .create_with_secret("workers", "worker-[0-9]+", pattern)
+        .unwrap();
+
+    assert_eq!(
+        token_store.rescind_by_token(&token.secret).unwrap(),
+        "leaked"
+    );
+    assert!(token_store.get("leaked").unwrap().is_none());
+    assert!(matches!(
+        token_store.rescind_by_token(&token.secret),
+        Err(TokenStoreError::UnknownSecret)
+    ));
+    // only the pattern matches, which is left for rescinding by label
+    assert!(matches!(
+        token_store.rescind_by_token("worker-1"),
+        Err(TokenStoreError::PatternOnly(label)) if label == "workers"
+    ));
+    assert!(token_store.get("workers").unwrap().is_some());
+}
+
+/// Serves `body` over HTTP to every request carrying the authorization,
+/// and 401 to any other, returning the URL to fetch it from.
+fn serve_token_list(body: &'static str, authorization: &'static str) -> String {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/tokens", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut authorised = false;
+            for line in BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                authorised |= line.split_once(':').is_some_and(|(name, value)| {
+                    name.eq_ignore_ascii_case("Authorization") && value.trim() == authorization
+                });
+            }
+            let response = match authorised {
+                true => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                false => {
+                    "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+                }
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    url
+}
+
+#[test]
+fn fetches_a_read_only_store_over_http() {
+    let url = serve_token_list(
+        "# mellon token store\nremote:a-fetched-secret\n",
+        "Bearer config-service",
+    );
+    let options = StoreOptions {
+        remote_authorization: Some("Bearer config-service".to_string()),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(url.clone(), options).unwrap();
+    assert!(token_store.is_remote());
+    assert!(token_store.contains_token("a-fetched-secret").unwrap());
+    assert!(matches!(
+        token_store.create("service", TokenOptions::default()),
+        Err(TokenStoreError::ReadOnly(_))
+    ));
+    assert!(token_store.contains_token("a-fetched-secret").unwrap());
+
+    // without the credential there is nothing to hold, but the store opens
+    // so that a later reload can still succeed
+    let token_store = TokenStore::new(url, StoreOptions::default()).unwrap();
+    assert!(!token_store.is_loaded());
+    assert!(matches!(
+        token_store.contains_token("a-fetched-secret"),
+        Err(TokenStoreError::NotLoaded)
+    ));
+}
+
+#[test]
+fn reads_a_store_encrypted_under_the_earlier_key_derivation() {
+    use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+    use sha2::{Digest, Sha256};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("tokens").to_string_lossy().into_owned();
+    let secret = b"3f8c0d6e9a7b41f2a5c8e1d04b7f9a26";
+
+    // written as stores were before the key was derived with HKDF
+    let mut hasher = Sha256::new();
+    hasher.update(b"mellon store data key\0");
+    hasher.update(secret);
+    let cipher = ChaCha20Poly1305::new(&hasher.finalize());
+    let nonce = [7; 12];
+    let plaintext = b"# mellon token store\nlegacy:an-encrypted-secret\n";
+    let mut data = b"MELLON-ENC1\n".to_vec();
+    data.extend_from_slice(&nonce);
+    data.extend(
+        cipher
+            .encrypt(Nonce::from_slice(&nonce), &plaintext[..])
+            .unwrap(),
+    );
+    std::fs::write(&path, data).unwrap();
+
+    let keyed = || StoreOptions {
+        key: Some(StoreKey::derive(secret).unwrap()),
+        ..StoreOptions::default()
+    };
+    let mut token_store = TokenStore::new(path.clone(), keyed()).unwrap();
+    assert!(token_store.contains_token("an-encrypted-secret").unwrap());
+
+    // the next change writes it under the current derivation
+    token_store
+        .create("service", TokenOptions::default())
+        .unwrap();
+    assert!(std::fs::read(&path).unwrap().starts_with(b"MELLON-ENC2\n"));
+    let token_store = TokenStore::new(path.clone(), keyed()).unwrap();
+    assert!(token_store.contains_token("an-encrypted-secret").unwrap());
+
+    let wrong_key = StoreOptions {
+        key: Some(StoreKey::derive(b"a different key").unwrap()),
+        ..StoreOptions::default()
+    };
+    assert!(matches!(
+        TokenStore::new(path, wrong_key),
+        Err(TokenStoreError::Key(_))
+    ));
+}